@@ -0,0 +1,113 @@
+//! `--bench-models`: one prompt sent to several models concurrently.
+
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use futures_util::future::join_all;
+
+use crate::chat::{extract_completion, ChatRequestBuilder};
+use crate::error::Result;
+use crate::session::HttpSession;
+use crate::vqd::VqdSession;
+
+/// Outcome of one model's run.
+#[derive(Debug)]
+pub struct BenchResult {
+    pub model: String,
+    pub elapsed: Duration,
+    /// Assembled completion length in characters, or the failure reason.
+    pub outcome: std::result::Result<usize, String>,
+}
+
+/// Sends `prompt` to every model at once; failures are reported per model.
+pub async fn run(
+    session: &HttpSession,
+    vqd: &VqdSession,
+    prompt: &str,
+    models: &[String],
+) -> Vec<BenchResult> {
+    join_all(models.iter().map(|model| async move {
+        let started = Instant::now();
+        let outcome = bench_one(session, vqd, prompt, model).await;
+        BenchResult {
+            model: model.clone(),
+            elapsed: started.elapsed(),
+            outcome: outcome.map_err(|err| format!("{err:#}")),
+        }
+    }))
+    .await
+}
+
+async fn bench_one(
+    session: &HttpSession,
+    vqd: &VqdSession,
+    prompt: &str,
+    model: &str,
+) -> Result<usize> {
+    let response = ChatRequestBuilder::new(prompt)
+        .model(model)
+        .send(session, vqd)
+        .await?;
+    if response.status != 200 {
+        return Err(anyhow!("HTTP {}", response.status));
+    }
+    Ok(extract_completion(&response.body).chars().count())
+}
+
+/// Renders the comparison table printed by the CLI.
+pub fn format_table(results: &[BenchResult]) -> String {
+    let width = results
+        .iter()
+        .map(|result| result.model.len())
+        .chain(std::iter::once("model".len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!(
+        "{:<width$}  {:>10}  {:>8}  error\n",
+        "model", "latency_ms", "chars"
+    );
+    for result in results {
+        let latency = result.elapsed.as_millis();
+        match &result.outcome {
+            Ok(chars) => table.push_str(&format!(
+                "{:<width$}  {latency:>10}  {chars:>8}\n",
+                result.model
+            )),
+            Err(err) => table.push_str(&format!(
+                "{:<width$}  {latency:>10}  {:>8}  {err}\n",
+                result.model, "-"
+            )),
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_lists_successes_and_failures() {
+        let table = format_table(&[
+            BenchResult {
+                model: "gpt-5-mini".to_owned(),
+                elapsed: Duration::from_millis(1200),
+                outcome: Ok(42),
+            },
+            BenchResult {
+                model: "openai/gpt-oss-120b".to_owned(),
+                elapsed: Duration::from_millis(30),
+                outcome: Err("HTTP 429".to_owned()),
+            },
+        ]);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines[1].starts_with("gpt-5-mini ")
+                && lines[1].contains("1200")
+                && lines[1].ends_with("42")
+        );
+        assert!(lines[2].contains("HTTP 429"));
+    }
+}
@@ -0,0 +1,341 @@
+//! Shared VQD session cache used by the OpenAI-compatible server.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Mutex, Notify, RwLock};
+
+use crate::error::Result;
+use crate::session::{HttpSession, SessionConfig};
+use crate::vqd::{self, VqdSession};
+
+/// Cached HTTP session together with the VQD metadata prepared on it.
+#[derive(Debug, Clone)]
+pub struct CachedSession {
+    pub session: Arc<HttpSession>,
+    pub vqd: Arc<VqdSession>,
+    pub prepared_at: Instant,
+}
+
+/// Lazily prepared session shared across requests.
+///
+/// Preparation is single-flight: when the cache is empty or stale, the first caller runs
+/// `prepare_session` while concurrent callers wait for that result instead of starting
+/// their own JS evaluation.
+///
+/// With [`SessionCache::keep_standby_warm`] running, a second session is kept prepared and
+/// promoted instantly when the primary expires or is rotated.
+#[derive(Debug)]
+pub struct SessionCache {
+    config: SessionConfig,
+    ttl: Duration,
+    slot: RwLock<Option<CachedSession>>,
+    refresh: Mutex<()>,
+    standby: Mutex<Option<CachedSession>>,
+    standby_taken: Notify,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Lookup counters for [`SessionCache::get`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl SessionCache {
+    pub fn new(config: SessionConfig, ttl: Duration) -> Self {
+        Self {
+            config,
+            ttl,
+            slot: RwLock::new(None),
+            refresh: Mutex::new(()),
+            standby: Mutex::new(None),
+            standby_taken: Notify::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Configuration new sessions are built from.
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    /// Returns the cached session, preparing a fresh one when missing or expired.
+    pub async fn get(&self) -> Result<CachedSession> {
+        let config = self.config.clone();
+        self.get_or_prepare_with(move || prepare(config)).await
+    }
+
+    /// Discards the cached session and prepares a brand-new replacement immediately. The
+    /// `--warm-standby` session is deliberately not used: it may already be half a TTL old.
+    pub async fn rotate(&self) -> Result<CachedSession> {
+        let config = self.config.clone();
+        self.rotate_with(move || prepare(config)).await
+    }
+
+    async fn rotate_with<F, Fut>(&self, prepare: F) -> Result<CachedSession>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<CachedSession>>,
+    {
+        let _guard = self.refresh.lock().await;
+        self.slot.write().await.take();
+        let cached = prepare().await?;
+        *self.slot.write().await = Some(cached.clone());
+        Ok(cached)
+    }
+
+    /// Drops the cached session if it is still the one that prepared `vqd`, so the next
+    /// [`get`](Self::get) prepares a new one. Returns whether it was dropped; a session
+    /// already replaced by a concurrent caller is left alone.
+    pub async fn invalidate(&self, vqd: &Arc<VqdSession>) -> bool {
+        let mut slot = self.slot.write().await;
+        let current = slot
+            .as_ref()
+            .is_some_and(|cached| Arc::ptr_eq(&cached.vqd, vqd));
+        if current {
+            slot.take();
+        }
+        current
+    }
+
+    /// Hit and miss counts since startup. Callers that waited on another caller's
+    /// preparation count as misses.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Time since the cached session was prepared, if there is one.
+    pub async fn age(&self) -> Option<Duration> {
+        self.slot
+            .read()
+            .await
+            .as_ref()
+            .map(|cached| cached.prepared_at.elapsed())
+    }
+
+    async fn get_or_prepare_with<F, Fut>(&self, prepare: F) -> Result<CachedSession>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<CachedSession>>,
+    {
+        if let Some(cached) = self.fresh().await {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(cached);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let _guard = self.refresh.lock().await;
+        // Another caller may have finished preparing while we waited for the lock.
+        if let Some(cached) = self.fresh().await {
+            return Ok(cached);
+        }
+
+        let cached = match self.take_standby().await {
+            Some(standby) => standby,
+            None => prepare().await?,
+        };
+        *self.slot.write().await = Some(cached.clone());
+        Ok(cached)
+    }
+
+    /// Keeps a standby session prepared for the lifetime of the server. The standby is
+    /// replaced once it is half a TTL old, so a promoted session still has at least half
+    /// its lifetime left, and is replaced right away after being promoted.
+    pub async fn keep_standby_warm(self: Arc<Self>) {
+        let interval = (self.ttl / 2).max(Duration::from_secs(1));
+        loop {
+            let config = self.config.clone();
+            if let Err(err) = self.refill_standby_with(move || prepare(config)).await {
+                tracing::warn!("failed to prepare standby session: {err:?}");
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = self.standby_taken.notified() => {}
+            }
+        }
+    }
+
+    async fn refill_standby_with<F, Fut>(&self, prepare: F) -> Result<()>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<CachedSession>>,
+    {
+        let stale = self
+            .standby
+            .lock()
+            .await
+            .as_ref()
+            .is_none_or(|standby| standby.prepared_at.elapsed() >= self.ttl / 2);
+        if stale {
+            let standby = prepare().await?;
+            *self.standby.lock().await = Some(standby);
+        }
+        Ok(())
+    }
+
+    /// Takes the standby session if it is still within its TTL.
+    async fn take_standby(&self) -> Option<CachedSession> {
+        let standby = self
+            .standby
+            .lock()
+            .await
+            .take()
+            .filter(|standby| standby.prepared_at.elapsed() < self.ttl)?;
+        tracing::debug!("promoted standby session");
+        self.standby_taken.notify_one();
+        Some(standby)
+    }
+
+    async fn fresh(&self) -> Option<CachedSession> {
+        self.slot
+            .read()
+            .await
+            .as_ref()
+            .filter(|cached| cached.prepared_at.elapsed() < self.ttl)
+            .cloned()
+    }
+}
+
+async fn prepare(config: SessionConfig) -> Result<CachedSession> {
+    let session = HttpSession::new(&config)?;
+    let vqd = vqd::prepare_session(&session, None).await?;
+    Ok(CachedSession {
+        session: Arc::new(session),
+        vqd: Arc::new(vqd),
+        prepared_at: Instant::now(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::transport::testing::test_config;
+
+    fn fake_session() -> CachedSession {
+        let config = test_config();
+        CachedSession {
+            session: Arc::new(HttpSession::new(&config).expect("session")),
+            vqd: Arc::new(crate::mock::vqd_session()),
+            prepared_at: Instant::now(),
+        }
+    }
+
+    fn test_cache(ttl: Duration) -> Arc<SessionCache> {
+        let config = test_config();
+        Arc::new(SessionCache::new(config, ttl))
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_preparation() {
+        let cache = test_cache(Duration::from_secs(60));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let mut handles = Vec::new();
+        for _ in 0..8 {
+            let cache = cache.clone();
+            let calls = calls.clone();
+            handles.push(tokio::spawn(async move {
+                cache
+                    .get_or_prepare_with(|| async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        Ok(fake_session())
+                    })
+                    .await
+                    .expect("prepared")
+            }));
+        }
+        for handle in handles {
+            handle.await.expect("task");
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().hits + cache.stats().misses, 8);
+        assert!(cache.age().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn invalidate_only_drops_the_rejected_session() {
+        let cache = test_cache(Duration::from_secs(60));
+        let first = cache
+            .get_or_prepare_with(|| async { Ok(fake_session()) })
+            .await
+            .expect("prepared");
+
+        // A stale handle from an already replaced session changes nothing.
+        let unrelated = fake_session();
+        assert!(!cache.invalidate(&unrelated.vqd).await);
+        assert!(cache.age().await.is_some());
+
+        assert!(cache.invalidate(&first.vqd).await);
+        assert!(cache.age().await.is_none());
+        assert!(!cache.invalidate(&first.vqd).await);
+
+        let second = cache
+            .get_or_prepare_with(|| async { Ok(fake_session()) })
+            .await
+            .expect("prepared again");
+        assert!(!Arc::ptr_eq(&first.vqd, &second.vqd));
+    }
+
+    #[tokio::test]
+    async fn expiry_promotes_the_standby_but_rotation_does_not() {
+        let cache = test_cache(Duration::from_secs(60));
+        let standby = fake_session();
+        let expected = standby.vqd.clone();
+        cache
+            .refill_standby_with(|| async { Ok(standby) })
+            .await
+            .expect("refilled");
+        // A fresh standby is not replaced.
+        cache
+            .refill_standby_with(|| async { panic!("standby is still fresh") })
+            .await
+            .expect("kept");
+
+        // Rotation always prepares a brand-new session and leaves the standby alone.
+        let rotated = cache
+            .rotate_with(|| async { Ok(fake_session()) })
+            .await
+            .expect("rotated");
+        assert!(!Arc::ptr_eq(&rotated.vqd, &expected));
+        assert!(cache.standby.lock().await.is_some());
+
+        assert!(cache.invalidate(&rotated.vqd).await);
+        let promoted = cache
+            .get_or_prepare_with(|| async { panic!("the standby is promoted instead") })
+            .await
+            .expect("promoted");
+        assert!(Arc::ptr_eq(&promoted.vqd, &expected));
+        assert!(cache.standby.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_prepared_again() {
+        let cache = test_cache(Duration::ZERO);
+        let calls = AtomicUsize::new(0);
+
+        for _ in 0..2 {
+            cache
+                .get_or_prepare_with(|| async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Ok(fake_session())
+                })
+                .await
+                .expect("prepared");
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
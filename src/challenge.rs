@@ -14,16 +14,57 @@ use serde_json::{json, Value};
 use tokio::{
     fs,
     net::TcpListener,
-    sync::{oneshot, Mutex},
+    sync::{mpsc, oneshot, Mutex},
     task::JoinHandle,
 };
 use url::form_urlencoded;
 
 use crate::error::Result;
 use crate::session::HttpSession;
+use crate::transport::TransportRequest;
 use crate::util::parse_tile_selection;
 
 const CHALLENGE_DIR: &str = "duckai_challenge";
+const CHALLENGE_FILE: &str = "challenge.json";
+const DEFAULT_CC: &str = "duckchat";
+const DEFAULT_SERVICE: &str = "aichat";
+const DEFAULT_REGION: &str = "usw";
+const DEFAULT_ATTEMPTS: usize = 3;
+
+/// User overrides for challenge verification parameters.
+///
+/// Values present in the challenge payload always win; these only replace the built-in
+/// defaults used when the payload omits a field.
+#[derive(Debug, Clone, Default)]
+pub struct ChallengeConfig {
+    pub cc: Option<String>,
+    pub service: Option<String>,
+    pub region: Option<String>,
+    /// Verification attempts before the challenge is abandoned.
+    pub attempts: Option<usize>,
+    /// Keep the payload as `duckai_challenge/challenge.json` for `--resume-challenge`. Set
+    /// for interactive CLI runs only; the server keeps payloads through
+    /// `--save-challenge-log` instead of writing to its working directory.
+    pub save_for_resume: bool,
+}
+
+impl ChallengeConfig {
+    pub fn cc(&self) -> &str {
+        self.cc.as_deref().unwrap_or(DEFAULT_CC)
+    }
+
+    pub fn service(&self) -> &str {
+        self.service.as_deref().unwrap_or(DEFAULT_SERVICE)
+    }
+
+    pub fn region(&self) -> &str {
+        self.region.as_deref().unwrap_or(DEFAULT_REGION)
+    }
+
+    pub fn attempts(&self) -> usize {
+        self.attempts.unwrap_or(DEFAULT_ATTEMPTS)
+    }
+}
 
 #[derive(Clone)]
 struct ChallengeAsset {
@@ -108,8 +149,59 @@ struct SubmitPayload {
     selections: Vec<usize>,
 }
 
+/// Summary of a challenge run, logged once handling finishes.
+#[derive(Debug, Default)]
+struct ChallengeReport {
+    tiles: usize,
+    web_ui: bool,
+    attempts: usize,
+    abandoned: bool,
+}
+
 /// Handles a server-issued challenge payload. Returns `true` when verification succeeds.
-pub async fn handle_challenge(session: &HttpSession, payload: &Value) -> Result<bool> {
+///
+/// When `progress` is set, a `{"action":"challenge_pending","url":...}` payload is sent on it
+/// each time manual verification is awaited, so stream consumers can surface the prompt.
+pub async fn handle_challenge(
+    session: &HttpSession,
+    payload: &Value,
+    progress: Option<&mpsc::Sender<String>>,
+) -> Result<bool> {
+    if session.challenge_config().save_for_resume {
+        match save_challenge_payload(payload).await {
+            Ok(path) => tracing::debug!("challenge payload saved to {}", path.display()),
+            Err(err) => {
+                tracing::warn!("could not keep the challenge for --resume-challenge: {err:?}")
+            }
+        }
+    }
+
+    let mut report = ChallengeReport::default();
+    let result = solve_challenge(session, payload, progress, &mut report).await;
+
+    let outcome = match &result {
+        Ok(true) => "solved",
+        Ok(false) if report.abandoned => "abandoned",
+        Ok(false) => "failed",
+        Err(_) => "error",
+    };
+    tracing::info!(
+        tiles = report.tiles,
+        mode = if report.web_ui { "web" } else { "cli" },
+        attempts = report.attempts,
+        outcome,
+        "challenge handling finished"
+    );
+
+    result
+}
+
+async fn solve_challenge(
+    session: &HttpSession,
+    payload: &Value,
+    progress: Option<&mpsc::Sender<String>>,
+    report: &mut ChallengeReport,
+) -> Result<bool> {
     let challenge = payload.get("cd").unwrap_or(payload);
 
     let override_code = challenge
@@ -124,10 +216,11 @@ pub async fn handle_challenge(session: &HttpSession, payload: &Value) -> Result<
         });
 
     if let Some(code) = override_code.as_deref() {
-        println!("Challenge overrideCode={code}");
+        tracing::info!("Challenge overrideCode={code}");
     }
 
     let tiles = extract_tiles(challenge);
+    report.tiles = tiles.len();
     if tiles.is_empty() {
         tracing::warn!("Challenge payload missing tile list: {payload}");
         return Ok(false);
@@ -136,20 +229,24 @@ pub async fn handle_challenge(session: &HttpSession, payload: &Value) -> Result<
     let assets = save_challenge_assets(session, &tiles).await?;
 
     if assets.is_empty() {
-        println!("未能下载挑战图片，挑战保持未完成。");
+        tracing::warn!("未能下载挑战图片，挑战保持未完成。");
         return Ok(false);
     }
 
-    const MAX_ATTEMPTS: usize = 3;
+    let max_attempts = session.challenge_config().attempts();
     let mut attempt = 0usize;
     let mut use_web = true;
 
     loop {
         attempt += 1;
+        report.attempts = attempt;
+        report.web_ui = use_web;
 
         let selected_indices = if use_web {
             match ChallengeWebServer::start(assets.clone()).await {
                 Ok((server, selection_rx)) => {
+                    notify_pending(progress, Some(&server.url())).await;
+                    // Instructions the user must act on go to stdout whatever the log level.
                     println!(
                         "挑战需要人工验证，请在浏览器打开 {} 并选择所有包含鸭子的图片后提交。",
                         server.url()
@@ -162,15 +259,17 @@ pub async fn handle_challenge(session: &HttpSession, payload: &Value) -> Result<
                     match result {
                         Ok(indices) => indices,
                         Err(_) => {
-                            println!("网页会话已结束，但未收到选择结果。");
+                            tracing::warn!("网页会话已结束，但未收到选择结果。");
                             Vec::new()
                         }
                     }
                 }
                 Err(err) => {
                     tracing::warn!("Failed to start challenge web interface: {err:?}");
-                    println!("无法启动本地网页，将回退到命令行输入模式。");
+                    tracing::warn!("无法启动本地网页，将回退到命令行输入模式。");
                     use_web = false;
+                    report.web_ui = false;
+                    notify_pending(progress, None).await;
                     println!(
                         "请打开目录 `{CHALLENGE_DIR}` 查看 JPG 文件，并手动选择所有包含鸭子的正方形。"
                     );
@@ -178,6 +277,7 @@ pub async fn handle_challenge(session: &HttpSession, payload: &Value) -> Result<
                 }
             }
         } else {
+            notify_pending(progress, None).await;
             println!(
                 "请打开目录 `{CHALLENGE_DIR}` 查看 JPG 文件，并手动选择所有包含鸭子的正方形。"
             );
@@ -185,8 +285,9 @@ pub async fn handle_challenge(session: &HttpSession, payload: &Value) -> Result<
         };
 
         if selected_indices.is_empty() {
-            println!("未选择任何图片，挑战保持未完成。");
-            if attempt >= MAX_ATTEMPTS {
+            tracing::warn!("未选择任何图片，挑战保持未完成。");
+            if attempt >= max_attempts {
+                report.abandoned = true;
                 return Ok(false);
             }
             println!("将重新发起挑战，请重新选择。");
@@ -198,8 +299,9 @@ pub async fn handle_challenge(session: &HttpSession, payload: &Value) -> Result<
             .filter(|&idx| idx < tiles.len())
             .collect::<Vec<_>>();
         if filtered.is_empty() {
-            println!("提交的索引无效，挑战保持未完成。");
-            if attempt >= MAX_ATTEMPTS {
+            tracing::warn!("提交的索引无效，挑战保持未完成。");
+            if attempt >= max_attempts {
+                report.abandoned = true;
                 return Ok(false);
             }
             println!("即将重新发起挑战，请检查输入。");
@@ -212,13 +314,13 @@ pub async fn handle_challenge(session: &HttpSession, payload: &Value) -> Result<
             .into_iter()
             .map(|idx| tiles[idx].clone())
             .collect::<Vec<_>>();
-        println!("已接收选择：{selected_ids:?}");
+        tracing::info!("已接收选择：{selected_ids:?}");
 
         match verify_challenge(session, challenge, &selected_ids).await? {
             true => return Ok(true),
             false => {
-                if attempt >= MAX_ATTEMPTS {
-                    println!("挑战验证失败次数过多，放弃本次挑战。");
+                if attempt >= max_attempts {
+                    tracing::warn!("挑战验证失败次数过多，放弃本次挑战。");
                     return Ok(false);
                 }
                 println!("挑战验证失败，将重新发起挑战，请重新选择。");
@@ -227,6 +329,13 @@ pub async fn handle_challenge(session: &HttpSession, payload: &Value) -> Result<
     }
 }
 
+async fn notify_pending(progress: Option<&mpsc::Sender<String>>, url: Option<&str>) {
+    if let Some(sender) = progress {
+        let event = json!({ "action": "challenge_pending", "url": url });
+        let _ = sender.send(event.to_string()).await;
+    }
+}
+
 fn extract_tiles(value: &Value) -> Vec<String> {
     value
         .get("p")
@@ -251,38 +360,51 @@ async fn save_challenge_assets(
         .await
         .context("creating duckai_challenge directory")?;
 
-    println!(
-        "Saving {} challenge tiles to `{}`",
-        tiles.len(),
-        dir.display()
-    );
-
     let mut assets = Vec::with_capacity(tiles.len());
+    let mut reused = 0usize;
 
     for (index, tile) in tiles.iter().enumerate() {
+        let filename = dir.join(format!("{:02}_{}.jpg", index + 1, tile));
+        if let Ok(existing) = fs::read(&filename).await {
+            if is_valid_jpeg(&existing) {
+                tracing::debug!(
+                    "[{}/{}] {} -> {} (reused)",
+                    index + 1,
+                    tiles.len(),
+                    tile,
+                    filename.display()
+                );
+                assets.push(ChallengeAsset {
+                    index,
+                    tile_id: tile.clone(),
+                    file_path: filename,
+                });
+                reused += 1;
+                continue;
+            }
+        }
+
         let url = session
             .base_url()
             .join(&format!("assets/anomaly/images/challenge/{tile}.jpg"))
             .context("building tile URL")?;
         let resp = session
-            .client()
-            .get(url)
-            .send()
+            .transport()
+            .send(TransportRequest::get(url))
             .await
             .with_context(|| format!("downloading tile {tile}"))?;
 
-        if !resp.status().is_success() {
-            tracing::warn!("Tile {tile} download failed with HTTP {}", resp.status());
+        if !resp.is_success() {
+            tracing::warn!("Tile {tile} download failed with HTTP {}", resp.status);
             continue;
         }
 
         let bytes = resp.bytes().await.context("reading tile bytes")?;
-        let filename = dir.join(format!("{:02}_{}.jpg", index + 1, tile));
         fs::write(&filename, bytes)
             .await
             .with_context(|| format!("writing tile to {}", filename.display()))?;
-        println!(
-            "  [{}/{}] {} -> {}",
+        tracing::debug!(
+            "[{}/{}] {} -> {}",
             index + 1,
             tiles.len(),
             tile,
@@ -297,11 +419,45 @@ async fn save_challenge_assets(
 
     if assets.is_empty() {
         tracing::warn!("No challenge tiles were saved successfully.");
+    } else {
+        tracing::info!(
+            "Saved {}/{} challenge tiles to `{}` ({reused} reused)",
+            assets.len(),
+            tiles.len(),
+            dir.display()
+        );
     }
 
     Ok(assets)
 }
 
+/// Accepts a previously saved tile only if it is a complete JPEG (SOI and EOI markers).
+fn is_valid_jpeg(bytes: &[u8]) -> bool {
+    bytes.len() > 4 && bytes.starts_with(&[0xFF, 0xD8]) && bytes.ends_with(&[0xFF, 0xD9])
+}
+
+/// Persists the challenge payload next to its tiles so `--resume-challenge` can pick it up.
+async fn save_challenge_payload(payload: &Value) -> Result<PathBuf> {
+    let path = PathBuf::from(CHALLENGE_DIR).join(CHALLENGE_FILE);
+    fs::create_dir_all(CHALLENGE_DIR)
+        .await
+        .context("creating duckai_challenge directory")?;
+    fs::write(&path, payload.to_string())
+        .await
+        .with_context(|| format!("writing challenge payload to {}", path.display()))?;
+    Ok(path)
+}
+
+/// Re-runs a challenge saved by an earlier session, reusing tiles already on disk.
+pub async fn resume_challenge(session: &HttpSession, path: &std::path::Path) -> Result<bool> {
+    let raw = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("reading challenge payload {}", path.display()))?;
+    let payload: Value = serde_json::from_str(&raw)
+        .with_context(|| format!("parsing challenge payload {}", path.display()))?;
+    handle_challenge(session, &payload, None).await
+}
+
 async fn challenge_page(State(state): State<ChallengeState>) -> Html<String> {
     let mut html = String::new();
     html.push_str(
@@ -525,7 +681,7 @@ async fn tile_image(
     Path(index): Path<usize>,
     State(state): State<ChallengeState>,
 ) -> impl IntoResponse {
-    match state.assets.get(index) {
+    match find_asset(&state.assets, index) {
         Some(asset) => match fs::read(&asset.file_path).await {
             Ok(bytes) => (
                 StatusCode::OK,
@@ -556,14 +712,7 @@ async fn submit_selection(
     State(state): State<ChallengeState>,
     Json(payload): Json<SubmitPayload>,
 ) -> impl IntoResponse {
-    let total = state.assets.len();
-    let mut selections: Vec<usize> = payload
-        .selections
-        .into_iter()
-        .filter(|&idx| idx < total)
-        .collect();
-    selections.sort_unstable();
-    selections.dedup();
+    let selections = retain_saved_indices(&state.assets, payload.selections);
 
     if selections.is_empty() {
         return (
@@ -598,6 +747,25 @@ async fn submit_selection(
     .into_response()
 }
 
+/// Looks up a saved asset by its original tile position.
+///
+/// Assets are only recorded for tiles that downloaded successfully, so positions in the
+/// `assets` vector do not line up with tile indices once a download fails.
+fn find_asset(assets: &[ChallengeAsset], index: usize) -> Option<&ChallengeAsset> {
+    assets.iter().find(|asset| asset.index == index)
+}
+
+/// Keeps only selections that refer to a saved tile, sorted and deduplicated.
+fn retain_saved_indices(assets: &[ChallengeAsset], selections: Vec<usize>) -> Vec<usize> {
+    let mut selections = selections
+        .into_iter()
+        .filter(|&idx| find_asset(assets, idx).is_some())
+        .collect::<Vec<_>>();
+    selections.sort_unstable();
+    selections.dedup();
+    selections
+}
+
 fn prompt_tile_selection(tiles: &[String]) -> Result<Vec<usize>> {
     println!("\n识别包含鸭子的图片：");
     for (idx, tile) in tiles.iter().enumerate() {
@@ -621,10 +789,11 @@ async fn verify_challenge(
         return Ok(false);
     }
 
+    let config = session.challenge_config();
     let q = string_field(challenge, "q").unwrap_or_default();
-    let cc = string_field(challenge, "cc").unwrap_or_else(|| "duckchat".to_owned());
-    let s_field = string_field(challenge, "s").unwrap_or_else(|| "aichat".to_owned());
-    let r_field = string_field(challenge, "r").unwrap_or_else(|| "usw".to_owned());
+    let cc = string_field(challenge, "cc").unwrap_or_else(|| config.cc().to_owned());
+    let s_field = string_field(challenge, "s").unwrap_or_else(|| config.service().to_owned());
+    let r_field = string_field(challenge, "r").unwrap_or_else(|| config.region().to_owned());
     let gk = string_field(challenge, "gk");
     let p_field = string_field(challenge, "p");
     let o_field = string_field(challenge, "o");
@@ -659,23 +828,22 @@ async fn verify_challenge(
         .join(&format!("anomaly.js?{params}"))
         .context("building challenge verification URL")?;
 
+    let request = TransportRequest::get(url).header("x-requested-with", "XMLHttpRequest")?;
     let resp = session
-        .client()
-        .get(url)
-        .header("X-Requested-With", "XMLHttpRequest")
-        .send()
+        .transport()
+        .send(request)
         .await
         .context("verifying challenge")?;
     let text = resp.text().await.context("reading verification response")?;
 
     match serde_json::from_str::<Value>(&text) {
         Ok(json) => {
-            println!("验证响应: {json}");
+            tracing::debug!("验证响应: {json}");
             if json.get("sc").and_then(|v| v.as_i64()) == Some(0) {
-                println!("挑战验证成功。");
+                tracing::info!("挑战验证成功。");
                 return Ok(true);
             }
-            println!("挑战验证失败。");
+            tracing::warn!("挑战验证失败。");
             Ok(false)
         }
         Err(err) => {
@@ -692,3 +860,61 @@ fn string_field(value: &Value, key: &str) -> Option<String> {
         .and_then(|v| v.as_str())
         .map(|s| s.to_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset(index: usize, tile_id: &str) -> ChallengeAsset {
+        ChallengeAsset {
+            index,
+            tile_id: tile_id.to_owned(),
+            file_path: PathBuf::from(format!("{:02}_{tile_id}.jpg", index + 1)),
+        }
+    }
+
+    #[test]
+    fn only_complete_jpegs_are_reused() {
+        assert!(is_valid_jpeg(&[0xFF, 0xD8, 0x00, 0x01, 0xFF, 0xD9]));
+        assert!(!is_valid_jpeg(&[0xFF, 0xD8, 0x00, 0x01, 0x02]));
+        assert!(!is_valid_jpeg(b"<html>"));
+        assert!(!is_valid_jpeg(&[]));
+    }
+
+    #[test]
+    fn challenge_overrides_replace_builtin_defaults() {
+        let defaults = ChallengeConfig::default();
+        assert_eq!(defaults.cc(), "duckchat");
+        assert_eq!(defaults.service(), "aichat");
+        assert_eq!(defaults.region(), "usw");
+        assert_eq!(defaults.attempts(), 3);
+
+        let config = ChallengeConfig {
+            region: Some("euw".to_owned()),
+            ..ChallengeConfig::default()
+        };
+        assert_eq!(config.region(), "euw");
+        assert_eq!(config.service(), "aichat");
+    }
+
+    #[test]
+    fn partial_downloads_keep_original_tile_indices() {
+        let tiles = ["a", "b", "c", "d"].map(str::to_owned);
+        // Tile `b` (index 1) failed to download.
+        let assets = vec![asset(0, "a"), asset(2, "c"), asset(3, "d")];
+
+        assert_eq!(
+            find_asset(&assets, 3).map(|a| a.tile_id.as_str()),
+            Some("d")
+        );
+        assert!(find_asset(&assets, 1).is_none());
+
+        let selected = retain_saved_indices(&assets, vec![3, 1, 2, 3, 7]);
+        assert_eq!(selected, vec![2, 3]);
+        let ids = selected
+            .into_iter()
+            .map(|idx| tiles[idx].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["c", "d"]);
+    }
+}
@@ -1,14 +1,23 @@
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use anyhow::{anyhow, Context};
 use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
 use base64::Engine;
 use futures_util::TryStreamExt;
-use serde_json::json;
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{json, Value};
 use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
 
 use crate::error::Result;
-use crate::session::HttpSession;
+use crate::model::DEFAULT_MODEL_ID;
+use crate::session::{retry_with_backoff, transient_response, HttpSession};
+use crate::sse::SseParser;
+use crate::transport::TransportRequest;
+use crate::util;
 use crate::vqd::VqdSession;
 
 /// Chat streaming response payload.
@@ -16,73 +25,328 @@ use crate::vqd::VqdSession;
 pub struct ChatResponse {
     pub status: u16,
     pub body: String,
+    /// Whether the upstream stream ended with its `[DONE]` terminal event.
+    pub completed: bool,
+    /// Whether a challenge was issued before this response.
+    pub challenged: bool,
+}
+
+/// Message roles understood by the DuckAI chat endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatRole {
+    User,
+    Assistant,
+}
+
+impl ChatRole {
+    /// Maps an OpenAI role onto a DuckAI role. DuckAI has no system role, so `system`,
+    /// `developer` and unknown roles are sent as user turns.
+    pub fn from_openai(role: &str) -> Self {
+        match role {
+            "assistant" => Self::Assistant,
+            _ => Self::User,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Assistant => "assistant",
+        }
+    }
+}
+
+/// One message of the conversation sent upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: ChatRole, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+
+    pub fn user(content: impl Into<String>) -> Self {
+        Self::new(ChatRole::User, content)
+    }
+}
+
+/// Per-request settings for [`send_chat`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChatOptions {
+    /// Falls back to the session's default temperature when unset.
+    pub temperature: Option<f64>,
+    /// Falls back to the session's timeout when unset.
+    pub timeout: Option<Duration>,
+    /// Sent upstream as `canUseTools`.
+    pub tools: bool,
+}
+
+/// Builds a chat request and drives [`send_chat`] without hand-assembling prompts.
+#[derive(Debug, Clone)]
+pub struct ChatRequestBuilder {
+    messages: Vec<ChatMessage>,
+    system: Option<String>,
+    model: String,
+    options: ChatOptions,
+    events: Option<mpsc::Sender<String>>,
+}
+
+impl ChatRequestBuilder {
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self::conversation(vec![ChatMessage::user(prompt)])
+    }
+
+    /// Sends a multi-turn conversation with each message keeping its role.
+    pub fn conversation(messages: Vec<ChatMessage>) -> Self {
+        Self {
+            messages,
+            system: None,
+            model: DEFAULT_MODEL_ID.to_owned(),
+            options: ChatOptions::default(),
+            events: None,
+        }
+    }
+
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Instructions sent ahead of the conversation as a `System:`-labelled user turn.
+    pub fn system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    /// Overrides the session's default temperature.
+    pub fn temperature(mut self, temperature: Option<f64>) -> Self {
+        self.options.temperature = temperature;
+        self
+    }
+
+    /// Overrides the session's upstream timeout for this request.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.options.timeout = timeout;
+        self
+    }
+
+    /// Lets the upstream model call Duck.ai's built-in tools (`canUseTools`).
+    pub fn tools(mut self, tools: bool) -> Self {
+        self.options.tools = tools;
+        self
+    }
+
+    /// Forwards raw upstream payloads to `events` while [`send`](Self::send) runs.
+    pub fn events(mut self, events: mpsc::Sender<String>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    fn payload_messages(&self) -> Vec<ChatMessage> {
+        let system = self
+            .system
+            .as_ref()
+            .map(|system| ChatMessage::user(format!("System: {system}")));
+        system.into_iter().chain(self.messages.clone()).collect()
+    }
+
+    /// Sends the request and returns the collected response.
+    pub async fn send(self, session: &HttpSession, vqd: &VqdSession) -> Result<ChatResponse> {
+        send_chat(
+            session,
+            vqd,
+            &self.payload_messages(),
+            &self.model,
+            self.options,
+            self.events,
+        )
+        .await
+    }
+
+    /// Streams assistant text as it arrives. The returned future drives the request and must
+    /// be polled alongside the stream; it resolves to the full response once upstream ends.
+    pub fn stream<'a>(
+        self,
+        session: &'a HttpSession,
+        vqd: &'a VqdSession,
+        buffer: usize,
+    ) -> (
+        impl Stream<Item = String>,
+        impl Future<Output = Result<ChatResponse>> + 'a,
+    ) {
+        let (event_tx, event_rx) = mpsc::channel::<String>(buffer);
+        let text = tokio_stream::StreamExt::filter_map(ReceiverStream::new(event_rx), |payload| {
+            payload_message(&payload)
+        });
+        let response = async move {
+            send_chat(
+                session,
+                vqd,
+                &self.payload_messages(),
+                &self.model,
+                self.options,
+                Some(event_tx),
+            )
+            .await
+        };
+        (text, response)
+    }
 }
 
 /// Send chat prompt using prepared session metadata.
 pub async fn send_chat(
     session: &HttpSession,
     vqd: &VqdSession,
-    prompt: &str,
+    messages: &[ChatMessage],
     model_id: &str,
+    options: ChatOptions,
     mut event_tx: Option<mpsc::Sender<String>>,
 ) -> Result<ChatResponse> {
-    const MAX_RETRIES: usize = 2;
+    if let Some(canned) = session.mock_response() {
+        return replay_canned(canned, event_tx).await;
+    }
 
+    let temperature = options.temperature.or(session.default_temperature());
     let url = session
         .base_url()
-        .join("duckchat/v1/chat")
+        .join(&session.paths().chat)
         .context("invalid chat url")?;
 
+    let mut challenged = false;
+    let mut send_signals = session.fe_signals();
+    let mut forwarded_content = false;
+    let payload = serde_json::to_vec(&build_chat_payload(
+        messages,
+        model_id,
+        temperature,
+        options.tools,
+    ))?;
     for attempt in 0..=MAX_RETRIES {
-        let request = session
-            .client()
-            .post(url.clone())
-            .header("Content-Type", "application/json")
-            .header("Accept", "text/event-stream")
-            .header("x-fe-version", &vqd.fe_version)
-            .header("x-vqd-hash-1", &vqd.vqd_header)
-            .header("x-fe-signals", format_fraud_signals());
-
-        let response = request
-            .json(&build_chat_payload(prompt, model_id))
-            .send()
-            .await
-            .context("sending chat request")?;
-
-        let status = response.status().as_u16();
-        let mut body = String::new();
-        let mut sse_buffer = String::new();
-
-        let mut stream = response.bytes_stream();
-        while let Some(chunk) = stream.try_next().await.context("reading chat stream")? {
-            let chunk_str = String::from_utf8_lossy(&chunk);
-            body.push_str(&chunk_str);
-
-            if status == 200 {
-                if let Some(sender) = event_tx.as_ref() {
-                    if !forward_sse_payloads(sender, &mut sse_buffer, &chunk_str).await {
-                        // Client dropped; stop forwarding but continue to consume response
-                        sse_buffer.clear();
-                        event_tx = None;
-                    }
+        let mut request = TransportRequest::post(url.clone(), payload.clone())
+            .timeout(options.timeout)
+            .header("content-type", "application/json")?
+            .header("accept", "text/event-stream")?
+            .header("x-fe-version", &vqd.fe_version)?
+            .header("x-vqd-hash-1", &vqd.vqd_header)?;
+        if send_signals {
+            request = request.header("x-fe-signals", &format_fraud_signals())?;
+        }
+
+        let response = retry_with_backoff(
+            session.retry_policy(),
+            "chat request",
+            || async {
+                // Every send, backoff retries included, honours --min-request-interval.
+                session.throttle().await;
+                session.transport().send(request.clone()).await
+            },
+            transient_response,
+        )
+        .await
+        .context("sending chat request")?;
+
+        let status = response.status;
+        let content_type = response
+            .header(CONTENT_TYPE.as_str())
+            .unwrap_or_default()
+            .to_owned();
+        let mut raw_body = Vec::new();
+        let mut parser = SseParser::default();
+        let mut completed = false;
+
+        let capped = caps_body(status, &content_type);
+        let mut stream = response.body;
+        let read_error = loop {
+            let chunk = match stream.try_next().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break None,
+                Err(err) => break Some(err),
+            };
+            if capped {
+                // Non-SSE bodies are only ever quoted in part; don't buffer a giant one.
+                if !append_capped(&mut raw_body, &chunk, session.max_error_body()) {
+                    tracing::warn!(
+                        "non-SSE upstream body exceeds {} bytes; truncating",
+                        session.max_error_body()
+                    );
+                    break None;
+                }
+                continue;
+            }
+            raw_body.extend_from_slice(&chunk);
+
+            let payloads = parser.push(&chunk);
+            completed |= payloads.iter().any(|payload| payload == "[DONE]");
+            if let Some(sender) = event_tx.as_ref() {
+                forwarded_content |= carries_content(&payloads);
+                if !forward_payloads(sender, payloads).await {
+                    // The consumer is gone (client disconnected or stalled past
+                    // --sse-send-timeout); stop reading so the upstream request is dropped
+                    // instead of streamed to the end for nobody.
+                    tracing::debug!("event receiver closed; abandoning the upstream stream");
+                    event_tx = None;
+                    break None;
                 }
             }
+        };
+        if let Some(err) = read_error {
+            if can_retry(attempt, forwarded_content) {
+                tracing::warn!(
+                    "chat stream failed before any content was forwarded; retrying: {err}"
+                );
+                continue;
+            }
+            let context = if forwarded_content {
+                "reading chat stream (not retried: partial output was already forwarded)"
+            } else {
+                "reading chat stream"
+            };
+            return Err(err.context(context));
+        }
+        let body = String::from_utf8_lossy(&raw_body).into_owned();
+
+        if status == 200 && looks_like_html(&content_type, &body) {
+            let snippet = body.trim().chars().take(200).collect::<String>();
+            return Err(anyhow!(
+                "upstream returned HTML (likely blocked or maintenance): {snippet}"
+            ));
         }
 
         if status == 200 {
+            let payloads = parser.finish();
+            completed |= payloads.iter().any(|payload| payload == "[DONE]");
             if let Some(sender) = event_tx.as_ref() {
-                if !sse_buffer.is_empty() {
-                    let _ = emit_event_block(sender, &sse_buffer).await;
+                if forward_payloads(sender, payloads).await {
+                    let _ = sender.send("[DONE]".to_owned()).await;
                 }
-                let _ = sender.send("[DONE]".to_owned()).await;
             }
         }
 
+        if send_signals && attempt < MAX_RETRIES && rejects_fe_signals(status, &body) {
+            tracing::warn!("upstream rejected x-fe-signals; retrying without it");
+            send_signals = false;
+            continue;
+        }
+
         if status == 418 {
+            challenged = true;
             match serde_json::from_str::<serde_json::Value>(&body) {
                 Ok(value) => {
                     tracing::warn!("Received challenge response: {value}");
-                    let solved = crate::challenge::handle_challenge(session, &value).await?;
+                    if let Some(dir) = session.challenge_log_dir() {
+                        if let Err(err) = save_challenge_log(dir, &value).await {
+                            tracing::warn!("failed to save challenge payload: {err:?}");
+                        }
+                    }
+                    let solved =
+                        crate::challenge::handle_challenge(session, &value, event_tx.as_ref())
+                            .await?;
                     if solved {
                         tracing::info!("Challenge solved; retrying chat (attempt {attempt})");
                         continue;
@@ -94,90 +358,260 @@ pub async fn send_chat(
             }
         }
 
-        return Ok(ChatResponse { status, body });
+        return Ok(ChatResponse {
+            status,
+            body,
+            completed,
+            challenged,
+        });
     }
 
-    Err(anyhow!(
-        "Reached maximum chat retries after handling challenge"
-    ))
+    Err(anyhow!("Reached maximum chat retries"))
 }
 
-async fn forward_sse_payloads(
-    sender: &mpsc::Sender<String>,
-    buffer: &mut String,
-    chunk: &str,
-) -> bool {
-    buffer.push_str(chunk);
+/// Further attempts [`send_chat`] makes after the first one.
+const MAX_RETRIES: usize = 2;
 
-    loop {
-        let (event_block, consumed) = match extract_event_block(buffer) {
-            Some(value) => value,
-            None => break,
-        };
+/// Chat requests have no upstream side effects, so a failed attempt may be sent again as
+/// long as none of its output reached the caller; resending after that would duplicate it.
+fn can_retry(attempt: usize, forwarded_content: bool) -> bool {
+    attempt < MAX_RETRIES && !forwarded_content
+}
 
-        if !emit_event_block(sender, &event_block).await {
-            return false;
+/// Appends as much of `chunk` as fits within `limit` bytes, returning `false` once
+/// something had to be dropped.
+fn append_capped(body: &mut Vec<u8>, chunk: &[u8], limit: usize) -> bool {
+    let room = limit.saturating_sub(body.len());
+    body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    chunk.len() <= room
+}
+
+/// Whether `--max-upstream-body` applies: to every body that is not the SSE stream, except
+/// 418 challenge payloads, which must be read whole to be solved. A 200 without a content
+/// type is read as SSE.
+fn caps_body(status: u16, content_type: &str) -> bool {
+    if status == 418 {
+        return false;
+    }
+    let sse = content_type
+        .to_ascii_lowercase()
+        .contains("text/event-stream")
+        || (status == 200 && content_type.is_empty());
+    !sse
+}
+
+/// Whether any of `payloads` carries assistant text.
+fn carries_content(payloads: &[String]) -> bool {
+    payloads
+        .iter()
+        .any(|payload| payload_message(payload).is_some_and(|message| !message.is_empty()))
+}
+
+/// Writes a raw challenge payload to a timestamped file (`--save-challenge-log`).
+async fn save_challenge_log(dir: &Path, value: &serde_json::Value) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("creating challenge log directory {}", dir.display()))?;
+    let path = dir.join(format!("challenge-{}.json", util::unix_millis()));
+    let pretty = serde_json::to_string_pretty(value).context("serializing challenge payload")?;
+    tokio::fs::write(&path, pretty)
+        .await
+        .with_context(|| format!("writing challenge payload to {}", path.display()))?;
+    tracing::info!("challenge payload saved to {}", path.display());
+    Ok(path)
+}
+
+/// Text carried by a `success` payload of the upstream stream, if any.
+pub fn payload_message(payload: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(payload).ok()?;
+    if value.get("action").and_then(|v| v.as_str()) != Some("success") {
+        return None;
+    }
+    value
+        .get("message")
+        .and_then(|v| v.as_str())
+        .map(str::to_owned)
+}
+
+/// Assistant text of a collected upstream body, or the trimmed body itself when it is not
+/// SSE at all (plain-text and error responses).
+pub fn extract_completion(body: &str) -> String {
+    let assembled = assemble_completion(body);
+    if assembled.is_empty() && !is_sse_body(body) {
+        body.trim().to_owned()
+    } else {
+        assembled
+    }
+}
+
+/// Whether the body consists solely of SSE `data:` lines (possibly none).
+pub fn is_sse_body(body: &str) -> bool {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .all(|line| line.starts_with("data:"))
+}
+
+/// Joins the text segments carried by an upstream body, ignoring the raw-body fallback.
+fn assemble_completion(body: &str) -> String {
+    let mut assembled = String::new();
+
+    for line in body.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let data = trimmed
+            .strip_prefix("data:")
+            .map(str::trim)
+            .unwrap_or(trimmed);
+        if data == "[DONE]" {
+            break;
         }
 
-        if consumed >= buffer.len() {
-            buffer.clear();
-        } else {
-            let remaining = buffer[consumed..].to_owned();
-            buffer.clear();
-            buffer.push_str(&remaining);
+        if let Ok(json) = serde_json::from_str::<Value>(data) {
+            if let Some(text) = json.get("message").and_then(Value::as_str) {
+                append_segment(&mut assembled, text);
+                continue;
+            }
+            if let Some(text) = json.get("content").and_then(|v| {
+                if v.is_array() {
+                    v.as_array().map(|items| {
+                        items
+                            .iter()
+                            .filter_map(|item| item.get("text").and_then(Value::as_str))
+                            .collect::<Vec<_>>()
+                            .join("")
+                    })
+                } else {
+                    v.as_str().map(|s| s.to_owned())
+                }
+            }) {
+                if !text.is_empty() {
+                    append_segment(&mut assembled, text.trim());
+                }
+                continue;
+            }
+            if let Some(text) = json.get("body").and_then(Value::as_str) {
+                append_segment(&mut assembled, text);
+                continue;
+            }
         }
+
+        append_segment(&mut assembled, data);
     }
 
-    true
+    assembled.trim().to_owned()
 }
 
-fn extract_event_block(buffer: &str) -> Option<(String, usize)> {
-    if let Some(pos) = buffer.find("\r\n\r\n") {
-        let block = buffer[..pos].to_owned();
-        return Some((block, pos + 4));
+fn append_segment(buffer: &mut String, segment: &str) {
+    let segment = segment.trim();
+    if segment.is_empty() {
+        return;
     }
-    if let Some(pos) = buffer.find("\n\n") {
-        let block = buffer[..pos].to_owned();
-        return Some((block, pos + 2));
+    if !buffer.is_empty() {
+        buffer.push('\n');
     }
-    None
+    buffer.push_str(segment);
 }
 
-async fn emit_event_block(sender: &mpsc::Sender<String>, block: &str) -> bool {
-    for line in block.lines() {
-        let line = line.trim_end_matches('\r');
-        if let Some(data) = line.strip_prefix("data:") {
-            let payload = data.trim_start();
-            if sender.send(payload.to_owned()).await.is_err() {
-                return false;
-            }
+/// Recognises a 400 whose JSON error blames the `x-fe-signals` header, either through its
+/// `type` (e.g. `ERR_INVALID_FE_SIGNALS`) or a message naming the header.
+fn rejects_fe_signals(status: u16, body: &str) -> bool {
+    if status != 400 {
+        return false;
+    }
+    let Ok(error) = serde_json::from_str::<Value>(body) else {
+        return false;
+    };
+    let field = |name: &str| {
+        error[name]
+            .as_str()
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+    };
+    field("type").contains("fe_signals") || field("message").contains("x-fe-signals")
+}
+
+/// Detects HTML block/maintenance pages served in place of the SSE stream.
+fn looks_like_html(content_type: &str, body: &str) -> bool {
+    if content_type.to_ascii_lowercase().contains("text/html") {
+        return true;
+    }
+    let head = body
+        .trim_start()
+        .chars()
+        .take(15)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    head.starts_with("<!doctype html") || head.starts_with("<html")
+}
+
+/// Feeds a canned SSE body through the same parsing and forwarding as a live stream.
+async fn replay_canned(body: &str, event_tx: Option<mpsc::Sender<String>>) -> Result<ChatResponse> {
+    let mut parser = SseParser::default();
+    let mut payloads = parser.push(body.as_bytes());
+    payloads.extend(parser.finish());
+    let completed = payloads.iter().any(|payload| payload == "[DONE]");
+
+    if let Some(sender) = event_tx.as_ref() {
+        if forward_payloads(sender, payloads).await {
+            let _ = sender.send("[DONE]".to_owned()).await;
+        }
+    }
+
+    Ok(ChatResponse {
+        status: 200,
+        body: body.to_owned(),
+        completed,
+        challenged: false,
+    })
+}
+
+async fn forward_payloads(sender: &mpsc::Sender<String>, payloads: Vec<String>) -> bool {
+    for payload in payloads {
+        if sender.send(payload).await.is_err() {
+            return false;
         }
     }
     true
 }
 
-fn build_chat_payload(prompt: &str, model_id: &str) -> serde_json::Value {
-    json!({
-        "model": model_id,
-        "metadata": serde_json::Map::<String, serde_json::Value>::new(),
-        "messages": [
-            {
-                "role": "user",
+fn build_chat_payload(
+    messages: &[ChatMessage],
+    model_id: &str,
+    temperature: Option<f64>,
+    tools: bool,
+) -> serde_json::Value {
+    let mut metadata = serde_json::Map::<String, serde_json::Value>::new();
+    if let Some(temperature) = temperature {
+        metadata.insert("temperature".to_owned(), json!(temperature));
+    }
+    let messages: Vec<_> = messages
+        .iter()
+        .map(|message| {
+            json!({
+                "role": message.role.as_str(),
                 "content": [
                     {
                         "type": "text",
-                        "text": prompt,
+                        "text": message.content,
                     }
                 ]
-            }
-        ],
-        "canUseTools": false,
+            })
+        })
+        .collect();
+    json!({
+        "model": model_id,
+        "metadata": metadata,
+        "messages": messages,
+        "canUseTools": tools,
         "canUseApproxLocation": false,
     })
 }
 
 fn format_fraud_signals() -> String {
-    let start = unix_millis();
+    let start = util::unix_millis();
     let events = json!([
         { "name": "onboarding_impression", "delta": 180 },
         { "name": "onboarding_finish", "delta": 22_600 },
@@ -185,37 +619,303 @@ fn format_fraud_signals() -> String {
     ]);
     let payload = json!({
         "start": start,
-        "end": unix_millis(),
+        "end": util::unix_millis(),
         "events": events,
     });
     BASE64_STANDARD.encode(payload.to_string())
 }
 
-fn unix_millis() -> u128 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or(Duration::from_secs(0))
-        .as_millis()
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use serde_json::Value;
+    use crate::transport::testing::{temp_path, test_config, Reply, ScriptedTransport};
 
     #[test]
     fn builds_chat_payload_structure() {
-        let payload = build_chat_payload("hi", "gpt-4o-mini");
+        let payload = build_chat_payload(&[ChatMessage::user("hi")], "gpt-4o-mini", None, false);
         assert_eq!(payload["model"], Value::String("gpt-4o-mini".into()));
+        assert_eq!(payload["messages"][0]["role"], "user");
+        assert!(payload["metadata"].get("temperature").is_none());
+        assert_eq!(payload["canUseTools"], false);
         assert_eq!(
             payload["messages"][0]["content"][0]["text"],
             Value::String("hi".into())
         );
+
+        let payload = build_chat_payload(&[ChatMessage::user("hi")], "gpt-4o-mini", None, true);
+        assert_eq!(payload["canUseTools"], true);
+    }
+
+    #[test]
+    fn chat_payload_carries_temperature_in_metadata() {
+        let payload =
+            build_chat_payload(&[ChatMessage::user("hi")], "gpt-4o-mini", Some(0.3), false);
+        assert_eq!(payload["metadata"]["temperature"], json!(0.3));
+    }
+
+    #[test]
+    fn error_bodies_are_capped_while_reading() {
+        let mut body = Vec::new();
+        assert!(append_capped(&mut body, b"abc", 5));
+        assert!(append_capped(&mut body, b"de", 5));
+        assert!(!append_capped(&mut body, b"f", 5));
+        assert_eq!(body, b"abcde");
+    }
+
+    #[test]
+    fn retries_only_before_content_was_forwarded() {
+        assert!(can_retry(0, false));
+        assert!(!can_retry(0, true));
+        assert!(!can_retry(MAX_RETRIES, false));
+
+        let empty = vec![r#"{"action":"success","message":""}"#.to_owned()];
+        assert!(!carries_content(&empty));
+        let text = vec![r#"{"action":"success","message":"Hi"}"#.to_owned()];
+        assert!(carries_content(&text));
+    }
+
+    #[test]
+    fn maps_openai_roles_into_payload() {
+        assert_eq!(ChatRole::from_openai("user"), ChatRole::User);
+        assert_eq!(ChatRole::from_openai("assistant"), ChatRole::Assistant);
+        assert_eq!(ChatRole::from_openai("system"), ChatRole::User);
+        assert_eq!(ChatRole::from_openai("developer"), ChatRole::User);
+        assert_eq!(ChatRole::from_openai("tool"), ChatRole::User);
+
+        let messages = [
+            ChatMessage::user("hi"),
+            ChatMessage::new(ChatRole::Assistant, "hello"),
+            ChatMessage::user("again"),
+        ];
+        let payload = build_chat_payload(&messages, "gpt-4o-mini", None, false);
+        let roles: Vec<_> = payload["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["user", "assistant", "user"]);
+        assert_eq!(payload["messages"][1]["content"][0]["text"], "hello");
+    }
+
+    #[tokio::test]
+    async fn builder_streams_text_from_mock_session() {
+        let mut config = test_config();
+        config.mock = Some(crate::mock::MockConfig::default());
+        let session = HttpSession::new(&config).expect("session");
+        let vqd = crate::vqd::prepare_session(&session, None)
+            .await
+            .expect("mock vqd");
+
+        let request = ChatRequestBuilder::new("hi").system("be brief");
+        assert_eq!(
+            request.payload_messages(),
+            [
+                ChatMessage::user("System: be brief"),
+                ChatMessage::user("hi")
+            ]
+        );
+
+        let (text, response) = request.stream(&session, &vqd, 16);
+        let text = tokio_stream::StreamExt::collect::<Vec<_>>(text);
+        let (response, text) = tokio::join!(response, text);
+        assert_eq!(response.expect("mock chat").status, 200);
+        assert_eq!(text.concat(), "Hello from the duckai-cli mock.");
+    }
+
+    #[tokio::test]
+    async fn challenge_log_writes_timestamped_payload() {
+        let dir = temp_path("challenges");
+        let payload = json!({ "cd": { "p": ["tile"] } });
+        let path = save_challenge_log(&dir, &payload).await.expect("saved");
+        let saved: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        assert_eq!(saved, payload);
+        assert!(path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("challenge-"));
+    }
+
+    #[test]
+    fn extracts_success_payload_messages() {
+        assert_eq!(
+            payload_message(r#"{"action":"success","message":"Hi"}"#).as_deref(),
+            Some("Hi")
+        );
+        assert_eq!(
+            payload_message(r#"{"action":"error","message":"no"}"#),
+            None
+        );
+        assert_eq!(payload_message("[DONE]"), None);
+    }
+
+    #[test]
+    fn recognises_fe_signals_rejections() {
+        assert!(rejects_fe_signals(
+            400,
+            r#"{"action":"error","type":"ERR_INVALID_FE_SIGNALS"}"#
+        ));
+        assert!(rejects_fe_signals(
+            400,
+            r#"{"action":"error","message":"Invalid x-fe-signals header"}"#
+        ));
+        assert!(!rejects_fe_signals(400, r#"{"type":"ERR_BN_LIMIT"}"#));
+        assert!(!rejects_fe_signals(
+            400,
+            r#"{"type":"ERR_BAD_REQUEST","message":"too many signals"}"#
+        ));
+        assert!(!rejects_fe_signals(400, "invalid fe signals"));
+        assert!(!rejects_fe_signals(
+            429,
+            r#"{"type":"ERR_INVALID_FE_SIGNALS"}"#
+        ));
+        assert!(!rejects_fe_signals(418, "signals"));
+        assert!(!rejects_fe_signals(200, "signals"));
+    }
+
+    #[test]
+    fn detects_html_pages() {
+        assert!(looks_like_html("text/html; charset=utf-8", ""));
+        assert!(looks_like_html(
+            "",
+            "\n  <!DOCTYPE html><html>maintenance</html>"
+        ));
+        assert!(looks_like_html("", "<HTML><body>blocked</body></HTML>"));
+        assert!(!looks_like_html(
+            "text/event-stream",
+            "data: {\"message\":\"<html>\"}\n\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn mock_session_replays_canned_stream() {
+        let mut config = test_config();
+        config.mock = Some(crate::mock::MockConfig::default());
+        let session = HttpSession::new(&config).expect("session");
+        let vqd = crate::vqd::prepare_session(&session, None)
+            .await
+            .expect("mock vqd");
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let response = send_chat(
+            &session,
+            &vqd,
+            &[ChatMessage::user("hi")],
+            "gpt-5-mini",
+            ChatOptions::default(),
+            Some(tx),
+        )
+        .await
+        .expect("mock chat");
+        assert_eq!(response.status, 200);
+        assert!(response.completed);
+
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
+        assert_eq!(events.last().map(String::as_str), Some("[DONE]"));
+        assert!(events[0].contains("Hello"));
+    }
+
+    #[tokio::test]
+    async fn chat_goes_through_the_session_transport() {
+        let mut config = test_config();
+        config.retry.base_delay = Duration::from_millis(1);
+        config.min_request_interval = Some(Duration::from_millis(100));
+        let started = std::time::Instant::now();
+        let transport = std::sync::Arc::new(ScriptedTransport::new([
+            Reply::Reset,
+            Reply::sse("data: {\"action\":\"success\",\"message\":\"Hi\"}\n\ndata: [DONE]\n\n"),
+        ]));
+        let session = HttpSession::new(&config)
+            .expect("session")
+            .with_transport(transport.clone());
+        let vqd = crate::mock::vqd_session();
+
+        let response = send_chat(
+            &session,
+            &vqd,
+            &[ChatMessage::user("hi")],
+            "gpt-5-mini",
+            ChatOptions {
+                timeout: Some(Duration::from_secs(90)),
+                ..ChatOptions::default()
+            },
+            None,
+        )
+        .await
+        .expect("chat after retry");
+        assert_eq!(response.status, 200);
+        assert!(response.completed);
+        // The backoff retry still waits out --min-request-interval.
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].method, reqwest::Method::POST);
+        assert_eq!(requests[1].headers["x-vqd-hash-1"], vqd.vqd_header.as_str());
+        assert_eq!(requests[1].timeout, Some(Duration::from_secs(90)));
+        let payload: Value = serde_json::from_slice(requests[1].body.as_ref().unwrap()).unwrap();
+        assert_eq!(payload["model"], "gpt-5-mini");
+    }
+
+    #[test]
+    fn caps_every_non_sse_body_but_challenges() {
+        assert!(!caps_body(200, "text/event-stream; charset=utf-8"));
+        assert!(!caps_body(200, ""));
+        assert!(caps_body(200, "text/html"));
+        assert!(caps_body(500, ""));
+        assert!(caps_body(429, "application/json"));
+        assert!(!caps_body(418, "application/json"));
+    }
+
+    #[test]
+    fn extracts_completion_text() {
+        let empty = "data: {\"action\":\"success\",\"message\":\"\"}\n\ndata: [DONE]\n\n";
+        assert_eq!(assemble_completion(empty), "");
+        assert_eq!(extract_completion(empty), "");
+        assert_eq!(extract_completion("  plain text  "), "plain text");
+    }
+
+    #[tokio::test]
+    async fn stops_reading_upstream_once_the_receiver_is_gone() {
+        let session = HttpSession::new(&test_config())
+            .expect("session")
+            .with_transport(std::sync::Arc::new(ScriptedTransport::new([
+                Reply::Endless("data: {\"action\":\"success\",\"message\":\"x\"}\n\n"),
+            ])));
+        let (tx, mut rx) = mpsc::channel(1);
+        let reader = tokio::spawn(async move {
+            rx.recv().await;
+        });
+
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            send_chat(
+                &session,
+                &crate::mock::vqd_session(),
+                &[ChatMessage::user("hi")],
+                "gpt-5-mini",
+                ChatOptions::default(),
+                Some(tx),
+            ),
+        )
+        .await
+        .expect("send_chat returns once nobody reads the events")
+        .unwrap();
+        assert!(!response.completed);
+        reader.await.unwrap();
     }
 
     #[test]
     fn fraud_signals_is_base64() {
         let signals = format_fraud_signals();
-        assert!(BASE64_STANDARD.decode(signals).expect("valid base64").len() > 0);
+        assert!(!BASE64_STANDARD
+            .decode(signals)
+            .expect("valid base64")
+            .is_empty());
     }
 }
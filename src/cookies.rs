@@ -0,0 +1,195 @@
+//! Cookie store that can be saved to and reloaded from disk for `--cookie-file`.
+//!
+//! `reqwest::cookie::Jar` cannot list its contents, so [`CookieJar`] wraps one and keeps
+//! the `Set-Cookie` lines it has accepted alongside it. `Max-Age` is turned into an absolute
+//! `Expires` when a cookie is recorded, so a reloaded cookie keeps its original lifetime.
+
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Context;
+use cookie::time::OffsetDateTime;
+use cookie::Cookie;
+use reqwest::cookie::{CookieStore, Jar};
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::util;
+
+/// One cookie as saved in the cookie file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredCookie {
+    /// URL of the response that set the cookie.
+    url: Url,
+    /// `Set-Cookie` value with an absolute `Expires`, if the cookie has one.
+    set_cookie: String,
+    /// Unix seconds after which the cookie is dropped; `None` for session cookies.
+    expires: Option<i64>,
+}
+
+impl StoredCookie {
+    fn parse(set_cookie: &str, url: &Url) -> Option<Self> {
+        let mut cookie = Cookie::parse(set_cookie.to_string()).ok()?;
+        if let Some(max_age) = cookie.max_age() {
+            cookie.set_max_age(None);
+            cookie.set_expires(OffsetDateTime::now_utc() + max_age);
+        }
+        Some(Self {
+            url: url.clone(),
+            expires: cookie.expires_datetime().map(|at| at.unix_timestamp()),
+            set_cookie: cookie.to_string(),
+        })
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Cookies replace each other when name, domain and path match.
+    fn same_cookie(&self, other: &Self) -> bool {
+        let key = |stored: &Self| {
+            Cookie::parse(stored.set_cookie.as_str())
+                .ok()
+                .map(|cookie| {
+                    (
+                        cookie.name().to_string(),
+                        cookie
+                            .domain()
+                            .map(str::to_string)
+                            .or_else(|| stored.url.host_str().map(str::to_string)),
+                        cookie.path().map(str::to_string),
+                    )
+                })
+        };
+        key(self).is_some() && key(self) == key(other)
+    }
+}
+
+/// reqwest cookie store whose contents can be persisted.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    jar: Jar,
+    stored: Mutex<Vec<StoredCookie>>,
+}
+
+impl CookieJar {
+    fn record(&self, cookie: StoredCookie) {
+        let mut stored = self.stored.lock().expect("cookie jar lock poisoned");
+        stored.retain(|existing| !existing.same_cookie(&cookie));
+        stored.push(cookie);
+    }
+
+    /// Loads cookies saved by [`save`](Self::save), skipping expired ones. A missing file
+    /// is an empty jar. Returns how many cookies were loaded.
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => {
+                return Err(err).with_context(|| format!("reading cookie file {}", path.display()))
+            }
+        };
+        let cookies: Vec<StoredCookie> = serde_json::from_slice(&bytes)
+            .with_context(|| format!("parsing cookie file {}", path.display()))?;
+
+        let now = util::unix_now() as i64;
+        let mut loaded = 0;
+        for cookie in cookies.into_iter().filter(|cookie| !cookie.is_expired(now)) {
+            self.jar.add_cookie_str(&cookie.set_cookie, &cookie.url);
+            self.record(cookie);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    /// Writes every unexpired cookie to `path`, readable by its owner only.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let now = util::unix_now() as i64;
+        let cookies: Vec<StoredCookie> = self
+            .stored
+            .lock()
+            .expect("cookie jar lock poisoned")
+            .iter()
+            .filter(|cookie| !cookie.is_expired(now))
+            .cloned()
+            .collect();
+        let bytes = serde_json::to_vec_pretty(&cookies)?;
+        util::write_private(path, &bytes)
+            .with_context(|| format!("writing cookie file {}", path.display()))
+    }
+}
+
+impl CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let headers: Vec<&HeaderValue> = cookie_headers.collect();
+        self.jar.set_cookies(&mut headers.iter().copied(), url);
+        for header in headers {
+            if let Some(cookie) = header
+                .to_str()
+                .ok()
+                .and_then(|value| StoredCookie::parse(value, url))
+            {
+                self.record(cookie);
+            }
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        self.jar.cookies(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::testing::temp_path;
+
+    fn set(jar: &CookieJar, url: &Url, value: &'static str) {
+        jar.set_cookies(&mut [HeaderValue::from_static(value)].iter(), url);
+    }
+
+    #[test]
+    fn round_trips_unexpired_cookies() {
+        let url: Url = "https://duckduckgo.com/duckchat/v1/status".parse().unwrap();
+        let path = temp_path("cookies.json");
+
+        let jar = CookieJar::default();
+        set(&jar, &url, "dcm=1; Path=/; Max-Age=3600");
+        set(&jar, &url, "dcm=2; Path=/; Max-Age=3600");
+        set(
+            &jar,
+            &url,
+            "gone=1; Path=/; Expires=Thu, 01 Jan 2015 00:00:00 GMT",
+        );
+        set(&jar, &url, "session=abc; Path=/");
+        jar.save(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let reloaded = CookieJar::default();
+        assert_eq!(reloaded.load(&path).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+        let header = reloaded.cookies(&url).unwrap();
+        let header = header.to_str().unwrap();
+        assert!(header.contains("dcm=2"));
+        assert!(header.contains("session=abc"));
+        assert!(!header.contains("gone"));
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_jar() {
+        let jar = CookieJar::default();
+        assert_eq!(
+            jar.load(Path::new("/nonexistent/duckai-cookies.json"))
+                .unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,414 @@
+//! HAR 1.2 capture of upstream traffic for `--har-output`.
+//!
+//! [`HarTransport`] wraps the session transport and records every exchange (status,
+//! homepage, chat stream, challenge tiles and verification). An entry is finished when its
+//! response body has been read or dropped and is then appended to the file in place of the
+//! closing brackets, which are written again after it. The file is valid HAR after every
+//! entry, and neither memory use nor the write per entry grows with the length of the run.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use base64::Engine;
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::stream::BoxStream;
+use futures_util::Stream;
+use reqwest::header::HeaderMap;
+use serde_json::{json, Value};
+
+use crate::error::Result;
+use crate::transport::{Transport, TransportRequest, TransportResponse};
+use crate::util;
+
+/// Headers whose values are replaced by a digest under `--redact`.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "set-cookie",
+    "x-vqd-4",
+    "x-vqd-hash-1",
+    "x-fe-signals",
+];
+
+/// Closes the `entries` array and the log object; rewritten after every entry.
+const LOG_TRAILER: &[u8] = b"\n]}}\n";
+
+/// Writes HAR entries to a file as they complete.
+#[derive(Debug)]
+pub struct HarRecorder {
+    path: PathBuf,
+    redact: bool,
+    file: Mutex<Option<HarFile>>,
+}
+
+/// The open HAR file and where its trailer starts.
+#[derive(Debug)]
+struct HarFile {
+    file: File,
+    trailer_at: u64,
+    entries: usize,
+}
+
+impl HarRecorder {
+    /// Records into `path`; nothing is written until the first exchange completes.
+    pub fn new(path: PathBuf, redact: bool) -> Self {
+        Self {
+            path,
+            redact,
+            file: Mutex::new(None),
+        }
+    }
+
+    fn push(&self, entry: Value) {
+        let mut file = self.file.lock().expect("HAR file lock poisoned");
+        if let Err(err) = self.append(&mut file, &entry) {
+            tracing::warn!(path = %self.path.display(), error = %err, "failed to write HAR file");
+        }
+    }
+
+    /// Writes `entry` over the trailer and puts the trailer back after it, creating the file
+    /// with the log header first.
+    fn append(&self, har: &mut Option<HarFile>, entry: &Value) -> Result<()> {
+        if har.is_none() {
+            let mut file = File::create(&self.path)?;
+            let creator = json!({
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            });
+            let header = format!(r#"{{"log":{{"version":"1.2","creator":{creator},"entries":["#);
+            file.write_all(header.as_bytes())?;
+            *har = Some(HarFile {
+                file,
+                trailer_at: header.len() as u64,
+                entries: 0,
+            });
+        }
+        let har = har.as_mut().expect("HAR file was just opened");
+
+        let mut bytes = if har.entries == 0 {
+            b"\n".to_vec()
+        } else {
+            b",\n".to_vec()
+        };
+        serde_json::to_writer(&mut bytes, entry)?;
+        har.file.seek(SeekFrom::Start(har.trailer_at))?;
+        har.file.write_all(&bytes)?;
+        har.file.write_all(LOG_TRAILER)?;
+        har.file.flush()?;
+        har.trailer_at += bytes.len() as u64;
+        har.entries += 1;
+        Ok(())
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> Vec<Value> {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes());
+                let value = if self.redact && SENSITIVE_HEADERS.contains(&name.as_str()) {
+                    util::redact(&value)
+                } else {
+                    value.into_owned()
+                };
+                json!({ "name": name.as_str(), "value": value })
+            })
+            .collect()
+    }
+
+    /// Body text for `postData`/`content`: UTF-8 as is, anything else base64-encoded, and
+    /// only a digest under `--redact`.
+    fn body(&self, bytes: &[u8]) -> (String, Option<&'static str>) {
+        match std::str::from_utf8(bytes) {
+            Ok(text) if self.redact => (util::redact(text), None),
+            Ok(text) => (text.to_string(), None),
+            Err(_) => {
+                let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+                if self.redact {
+                    (util::redact(&encoded), None)
+                } else {
+                    (encoded, Some("base64"))
+                }
+            }
+        }
+    }
+
+    fn request(&self, request: &TransportRequest, session_headers: &HeaderMap) -> Value {
+        let mut headers = session_headers.clone();
+        headers.extend(request.headers.clone());
+        let query: Vec<Value> = request
+            .url
+            .query_pairs()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+        let mut entry = json!({
+            "method": request.method.as_str(),
+            "url": request.url.as_str(),
+            "httpVersion": "unknown",
+            "cookies": [],
+            "headers": self.headers(&headers),
+            "queryString": query,
+            "headersSize": -1,
+            "bodySize": request.body.as_ref().map_or(0, |body| body.len() as i64),
+        });
+        if let Some(body) = &request.body {
+            let mime_type = headers
+                .get("content-type")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("");
+            let (text, _) = self.body(body);
+            entry["postData"] = json!({ "mimeType": mime_type, "text": text });
+        }
+        entry
+    }
+}
+
+/// Transport that records every exchange made through `inner`.
+#[derive(Debug)]
+pub struct HarTransport {
+    inner: Arc<dyn Transport>,
+    recorder: Arc<HarRecorder>,
+    /// Headers the reqwest client adds to every request, so entries show what was sent.
+    session_headers: HeaderMap,
+}
+
+impl HarTransport {
+    pub fn new(
+        inner: Arc<dyn Transport>,
+        recorder: Arc<HarRecorder>,
+        session_headers: HeaderMap,
+    ) -> Self {
+        Self {
+            inner,
+            recorder,
+            session_headers,
+        }
+    }
+}
+
+impl Transport for HarTransport {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse>> {
+        Box::pin(async move {
+            let started = SystemTime::now();
+            let clock = Instant::now();
+            let har_request = self.recorder.request(&request, &self.session_headers);
+            let pending = PendingEntry {
+                recorder: self.recorder.clone(),
+                started,
+                clock,
+                request: har_request,
+            };
+
+            match self.inner.send(request).await {
+                Ok(response) => {
+                    let body = RecordingBody {
+                        inner: response.body,
+                        buf: Vec::new(),
+                        error: None,
+                        status: response.status,
+                        headers: response.headers.clone(),
+                        wait_ms: clock.elapsed().as_secs_f64() * 1000.0,
+                        pending: Some(pending),
+                    };
+                    Ok(TransportResponse {
+                        status: response.status,
+                        headers: response.headers,
+                        body: Box::pin(body) as BoxStream<'static, Result<Bytes>>,
+                    })
+                }
+                Err(err) => {
+                    pending.finish(0, &HeaderMap::new(), &[], Some(err.to_string()), 0.0);
+                    Err(err)
+                }
+            }
+        })
+    }
+}
+
+/// Request half of an entry, waiting for its response body.
+struct PendingEntry {
+    recorder: Arc<HarRecorder>,
+    started: SystemTime,
+    clock: Instant,
+    request: Value,
+}
+
+impl PendingEntry {
+    fn finish(
+        self,
+        status: u16,
+        headers: &HeaderMap,
+        body: &[u8],
+        error: Option<String>,
+        wait_ms: f64,
+    ) {
+        let total_ms = self.clock.elapsed().as_secs_f64() * 1000.0;
+        let mime_type = headers
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        let (text, encoding) = self.recorder.body(body);
+        let mut content = json!({
+            "size": body.len(),
+            "mimeType": mime_type,
+            "text": text,
+        });
+        if let Some(encoding) = encoding {
+            content["encoding"] = json!(encoding);
+        }
+        let status_text = reqwest::StatusCode::from_u16(status)
+            .ok()
+            .and_then(|code| code.canonical_reason())
+            .unwrap_or("");
+        let mut entry = json!({
+            "startedDateTime": humantime::format_rfc3339_millis(self.started).to_string(),
+            "time": total_ms,
+            "request": self.request,
+            "response": {
+                "status": status,
+                "statusText": status_text,
+                "httpVersion": "unknown",
+                "cookies": [],
+                "headers": self.recorder.headers(headers),
+                "content": content,
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": body.len(),
+            },
+            "cache": {},
+            "timings": {
+                "send": 0,
+                "wait": wait_ms,
+                "receive": total_ms - wait_ms,
+            },
+        });
+        if let Some(error) = error {
+            entry["_error"] = json!(error);
+        }
+        self.recorder.push(entry);
+    }
+}
+
+/// Response body that copies chunks as they pass and completes the entry once read or
+/// dropped.
+struct RecordingBody {
+    inner: BoxStream<'static, Result<Bytes>>,
+    buf: Vec<u8>,
+    error: Option<String>,
+    status: u16,
+    headers: HeaderMap,
+    wait_ms: f64,
+    pending: Option<PendingEntry>,
+}
+
+impl Stream for RecordingBody {
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = self.inner.as_mut().poll_next(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => self.buf.extend_from_slice(chunk),
+            Poll::Ready(Some(Err(err))) => self.error = Some(err.to_string()),
+            _ => {}
+        }
+        polled
+    }
+}
+
+impl Drop for RecordingBody {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            pending.finish(
+                self.status,
+                &self.headers,
+                &self.buf,
+                self.error.take(),
+                self.wait_ms,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::testing::{temp_path, Reply, ScriptedTransport};
+    use reqwest::header::HeaderValue;
+
+    async fn record(redact: bool) -> Value {
+        let path = temp_path(&format!("{redact}.har"));
+        let recorder = Arc::new(HarRecorder::new(path.clone(), redact));
+        let mut session_headers = HeaderMap::new();
+        session_headers.insert("user-agent", HeaderValue::from_static("test-agent"));
+        let upstream = ScriptedTransport::new([Reply::Body {
+            status: 200,
+            headers: vec![
+                ("content-type", "text/plain"),
+                ("x-vqd-hash-1", "secret-hash"),
+            ],
+            chunks: vec!["he", "llo"],
+        }]);
+        let transport = HarTransport::new(Arc::new(upstream), recorder, session_headers);
+
+        let request = TransportRequest::post(
+            "https://duck.test/chat?x=1".parse().unwrap(),
+            b"{\"prompt\":\"hi\"}".to_vec(),
+        )
+        .header("content-type", "application/json")
+        .unwrap();
+        let response = transport.send(request.clone()).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "hello");
+        transport.send(request).await.unwrap();
+
+        let har = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        serde_json::from_slice(&har).unwrap()
+    }
+
+    #[tokio::test]
+    async fn records_the_exchange_as_har() {
+        let har = record(false).await;
+        assert_eq!(har["log"]["version"], "1.2");
+        assert_eq!(har["log"]["entries"].as_array().unwrap().len(), 2);
+        let entry = &har["log"]["entries"][0];
+        assert_eq!(entry["request"]["method"], "POST");
+        assert_eq!(entry["request"]["queryString"][0]["value"], "1");
+        assert_eq!(entry["request"]["postData"]["text"], "{\"prompt\":\"hi\"}");
+        assert!(entry["request"]["headers"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "name": "user-agent", "value": "test-agent" })));
+        assert_eq!(entry["response"]["status"], 200);
+        assert_eq!(entry["response"]["content"]["text"], "hello");
+        assert!(entry["response"]["headers"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "name": "x-vqd-hash-1", "value": "secret-hash" })));
+    }
+
+    #[tokio::test]
+    async fn redacts_sensitive_headers_and_bodies() {
+        let har = record(true).await;
+        let entry = &har["log"]["entries"][0];
+        let hash = entry["response"]["headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|header| header["name"] == "x-vqd-hash-1")
+            .unwrap();
+        assert!(hash["value"].as_str().unwrap().starts_with("<redacted"));
+        assert!(entry["request"]["postData"]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("<redacted"));
+        assert!(entry["response"]["content"]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("<redacted"));
+    }
+}
@@ -0,0 +1,173 @@
+//! SQLite-backed history of completed chat requests (`--db`).
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context};
+use rusqlite::{params, Connection};
+
+use crate::chat::{extract_completion, ChatResponse};
+use crate::error::Result;
+use crate::util;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    model TEXT NOT NULL,
+    prompt TEXT NOT NULL,
+    response TEXT NOT NULL,
+    status INTEGER NOT NULL,
+    challenged INTEGER NOT NULL
+)";
+
+/// One recorded chat request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Unix time in seconds.
+    pub timestamp: i64,
+    pub model: String,
+    pub prompt: String,
+    pub response: String,
+    /// Upstream HTTP status of the final chat attempt.
+    pub status: u16,
+    /// Whether a challenge was issued while handling the request.
+    pub challenged: bool,
+}
+
+impl HistoryEntry {
+    /// Captures a finished exchange, storing the assembled completion for successful responses.
+    /// With `redact` (`--redact`) the prompt and response are stored as length and digest only.
+    pub fn new(model: &str, prompt: &str, response: &ChatResponse, redact: bool) -> Self {
+        let text = if response.status == 200 {
+            extract_completion(&response.body)
+        } else {
+            response.body.clone()
+        };
+        Self {
+            timestamp: util::unix_now() as i64,
+            model: model.to_owned(),
+            prompt: util::loggable(prompt, redact).into_owned(),
+            response: util::loggable(&text, redact).into_owned(),
+            status: response.status,
+            challenged: response.challenged,
+        }
+    }
+}
+
+/// History database shared by the CLI and the server.
+#[derive(Debug)]
+pub struct HistoryDb {
+    conn: Mutex<Connection>,
+}
+
+impl HistoryDb {
+    /// Opens (creating if needed) the history database at `path`.
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening history database {}", path.display()))?;
+        Self::init(conn)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute(SCHEMA, []).context("creating history table")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn record(&self, entry: &HistoryEntry) -> Result<()> {
+        self.lock()?
+            .execute(
+                "INSERT INTO history (timestamp, model, prompt, response, status, challenged)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    entry.timestamp,
+                    entry.model,
+                    entry.prompt,
+                    entry.response,
+                    entry.status,
+                    entry.challenged,
+                ],
+            )
+            .context("recording history entry")?;
+        Ok(())
+    }
+
+    /// Returns up to `limit` entries, newest first.
+    pub fn recent(&self, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let conn = self.lock()?;
+        let mut statement = conn.prepare(
+            "SELECT timestamp, model, prompt, response, status, challenged
+             FROM history ORDER BY id DESC LIMIT ?1",
+        )?;
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let rows = statement.query_map([limit], |row| {
+            Ok(HistoryEntry {
+                timestamp: row.get(0)?,
+                model: row.get(1)?,
+                prompt: row.get(2)?,
+                response: row.get(3)?,
+                status: row.get(4)?,
+                challenged: row.get(5)?,
+            })
+        })?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()
+            .context("reading history entries")
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Connection>> {
+        self.conn
+            .lock()
+            .map_err(|_| anyhow!("history database lock poisoned"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(prompt: &str) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: 1_700_000_000,
+            model: "gpt-5-mini".to_owned(),
+            prompt: prompt.to_owned(),
+            response: "reply".to_owned(),
+            status: 200,
+            challenged: false,
+        }
+    }
+
+    #[test]
+    fn recent_returns_newest_first() {
+        let db = HistoryDb::init(Connection::open_in_memory().unwrap()).unwrap();
+        db.record(&entry("first")).unwrap();
+        db.record(&HistoryEntry {
+            challenged: true,
+            ..entry("second")
+        })
+        .unwrap();
+
+        let recent = db.recent(10).unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].prompt, "second");
+        assert!(recent[0].challenged);
+        assert_eq!(db.recent(1).unwrap(), vec![recent[0].clone()]);
+    }
+
+    #[test]
+    fn redacted_entries_keep_no_content() {
+        let response = ChatResponse {
+            status: 200,
+            body: "data: {\"action\":\"success\",\"message\":\"secret reply\"}\n\n".to_owned(),
+            completed: true,
+            challenged: false,
+        };
+        let plain = HistoryEntry::new("gpt-5-mini", "secret prompt", &response, false);
+        assert_eq!(plain.prompt, "secret prompt");
+        assert_eq!(plain.response, "secret reply");
+
+        let redacted = HistoryEntry::new("gpt-5-mini", "secret prompt", &response, true);
+        assert!(redacted.prompt.starts_with("<redacted"));
+        assert!(!redacted.response.contains("secret"));
+    }
+}
@@ -1,7 +1,10 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
 use boa_engine::js_string;
 use boa_engine::property::Attribute;
 use boa_engine::{Context as BoaContext, JsError, JsValue, Source};
@@ -23,7 +26,37 @@ struct RawHashes {
     meta: serde_json::Value,
 }
 
-pub fn evaluate(script_b64: &str, user_agent: &str) -> anyhow::Result<EvaluatedHashes> {
+/// Script standing in for the upstream VQD script during the runtime self-test.
+const SELFTEST_SCRIPT: &str =
+    "Promise.resolve({ server_hashes: ['selftest'], client_hashes: [String(typeof navigator)] })";
+
+/// Evaluates a known fixture through the bundled runtime, independent of any upstream script.
+///
+/// A failure here means the embedded runtime (or the pinned boa version) regressed, not that
+/// Duck.ai rotated its script.
+pub fn selftest() -> anyhow::Result<()> {
+    let hashes = evaluate(
+        &BASE64_STANDARD.encode(SELFTEST_SCRIPT),
+        "duckai-selftest",
+        &AtomicBool::new(false),
+    )
+    .map_err(|err| anyhow!("JS runtime self-test failed (bundled runtime is broken): {err:#}"))?;
+    if hashes.server_hashes != ["selftest"] || hashes.client_hashes.len() != 1 {
+        return Err(anyhow!(
+            "JS runtime self-test returned unexpected hashes: {:?} / {:?}",
+            hashes.server_hashes,
+            hashes.client_hashes
+        ));
+    }
+    Ok(())
+}
+
+/// Runs the VQD script. Polling stops early once `cancel` is set, e.g. on server shutdown.
+pub fn evaluate(
+    script_b64: &str,
+    user_agent: &str,
+    cancel: &AtomicBool,
+) -> anyhow::Result<EvaluatedHashes> {
     let mut context = BoaContext::default();
     eval_source(
         &mut context,
@@ -63,6 +96,9 @@ pub fn evaluate(script_b64: &str, user_agent: &str) -> anyhow::Result<EvaluatedH
     let deadline = Instant::now() + Duration::from_secs(5);
     let mut iterations = 0;
     loop {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(anyhow!("JS evaluation cancelled by shutdown"));
+        }
         context.run_jobs();
 
         let result = get_global(&mut context, "__duckai_result")?;
@@ -118,14 +154,52 @@ fn js_value_to_string(
     value: JsValue,
     label: &str,
 ) -> anyhow::Result<String> {
-    value
+    let string = value
         .to_string(context)
-        .map_err(|err| js_error_to_anyhow(err, label))?
-        .to_std_string()
-        .map_err(|_| anyhow!("{label}: produced non-utf8 string", label = label))
+        .map_err(|err| js_error_to_anyhow(err, label))?;
+    Ok(string.to_std_string().unwrap_or_else(|_| {
+        // Unpaired surrogates rarely touch the hash fields, so keep evaluating.
+        tracing::debug!("{label}: non-utf8 string converted lossily");
+        String::from_utf16_lossy(&string)
+    }))
 }
 
 fn js_error_to_anyhow(err: JsError, label: &str) -> anyhow::Error {
     let message = err.to_string();
     anyhow!("{label}: {message}", label = label, message = message)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_selftest_passes() {
+        selftest().expect("bundled runtime evaluates the fixture");
+    }
+
+    #[test]
+    fn cancellation_stops_a_pending_evaluation() {
+        let cancel = std::sync::Arc::new(AtomicBool::new(false));
+        let trip = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            trip.store(true, Ordering::SeqCst);
+        });
+        let started = Instant::now();
+        let never = BASE64_STANDARD.encode("new Promise(() => {})");
+        let err = evaluate(&never, "UA", &cancel).unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err:#}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn unpaired_surrogates_convert_lossily() {
+        let mut context = BoaContext::default();
+        let value = context
+            .eval(Source::from_bytes(r#""ok\uD800""#))
+            .expect("evaluates");
+        let text = js_value_to_string(&mut context, value, "test").expect("lossy string");
+        assert_eq!(text, "ok\u{FFFD}");
+    }
+}
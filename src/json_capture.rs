@@ -0,0 +1,121 @@
+//! Incremental capture of a single JSON value from streamed model output, used for
+//! `response_format: json_object` requests.
+
+/// Buffers streamed text until one complete top-level JSON object or array has arrived.
+///
+/// Anything before the opening `{`/`[` (such as a Markdown code fence) and anything after
+/// the value closes is discarded.
+#[derive(Debug, Default)]
+pub struct JsonCapture {
+    buf: String,
+    /// Closing characters still expected, innermost last.
+    closers: Vec<char>,
+    in_string: bool,
+    escaped: bool,
+    done: bool,
+}
+
+impl JsonCapture {
+    /// Feeds a delta, returning the whole value once its final closing bracket arrives.
+    pub fn push(&mut self, text: &str) -> Option<String> {
+        if self.done {
+            return None;
+        }
+        for ch in text.chars() {
+            if self.closers.is_empty() {
+                match ch {
+                    '{' => self.closers.push('}'),
+                    '[' => self.closers.push(']'),
+                    _ => continue,
+                }
+                self.buf.push(ch);
+                continue;
+            }
+
+            self.buf.push(ch);
+            if self.in_string {
+                match ch {
+                    _ if self.escaped => self.escaped = false,
+                    '\\' => self.escaped = true,
+                    '"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match ch {
+                '"' => self.in_string = true,
+                '{' => self.closers.push('}'),
+                '[' => self.closers.push(']'),
+                '}' | ']' => {
+                    self.closers.pop();
+                    if self.closers.is_empty() {
+                        self.done = true;
+                        return Some(std::mem::take(&mut self.buf));
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Best-effort repair of a value cut off by the end of the stream: closes an open
+    /// string, drops a dangling separator, and closes every open container.
+    pub fn finish(&mut self) -> Option<String> {
+        if self.done || self.closers.is_empty() {
+            return None;
+        }
+        self.done = true;
+        let mut repaired = std::mem::take(&mut self.buf);
+        if self.in_string {
+            if self.escaped {
+                repaired.pop();
+            }
+            repaired.push('"');
+        }
+        let kept = repaired.trim_end().trim_end_matches(',').len();
+        repaired.truncate(kept);
+        if repaired.ends_with(':') {
+            repaired.push_str("null");
+        }
+        repaired.extend(self.closers.drain(..).rev());
+        Some(repaired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emits_the_value_once_complete() {
+        let mut capture = JsonCapture::default();
+        assert_eq!(capture.push("```json\n{\"a\": \"}"), None);
+        assert_eq!(capture.push("[\", \"b\": [1, {"), None);
+        assert_eq!(
+            capture.push("}]} trailing```").as_deref(),
+            Some(r#"{"a": "}[", "b": [1, {}]}"#)
+        );
+        assert_eq!(capture.push("{}"), None);
+        assert_eq!(capture.finish(), None);
+    }
+
+    #[test]
+    fn repairs_a_truncated_value() {
+        let mut capture = JsonCapture::default();
+        capture.push(r#"{"items": [{"name": "du\"ck"#);
+        let repaired = capture.finish().unwrap();
+        assert_eq!(repaired, r#"{"items": [{"name": "du\"ck"}]}"#);
+        assert!(serde_json::from_str::<serde_json::Value>(&repaired).is_ok());
+
+        let mut capture = JsonCapture::default();
+        capture.push(r#"{"a": 1, "b":"#);
+        let repaired = capture.finish().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&repaired).unwrap()["b"],
+            serde_json::Value::Null
+        );
+
+        assert_eq!(JsonCapture::default().finish(), None);
+    }
+}
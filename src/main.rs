@@ -1,35 +1,161 @@
+mod bench;
+mod cache;
 mod challenge;
 mod chat;
 mod cli;
+mod cookies;
 mod error;
+mod har;
+mod history;
 mod js;
+mod json_capture;
+mod mock;
 mod model;
 mod server;
 mod session;
+mod sse;
+mod stats;
+mod transport;
 mod util;
 mod vqd;
 
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::AtomicBool;
+
+use anyhow::anyhow;
 use clap::Parser;
-use cli::CliArgs;
+use cli::{CliArgs, Command};
+use dialoguer::Select;
 use error::Result;
+use futures_util::StreamExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
 
-fn init_tracing() {
+fn init_tracing(ansi: bool, verbose: bool) {
     use tracing_subscriber::{fmt, EnvFilter};
 
-    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let default_filter = if verbose {
+        "info,duckai_cli=debug"
+    } else {
+        "info"
+    };
+    let env_filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_filter));
 
     let _ = fmt()
         .with_env_filter(env_filter)
         .with_target(false)
+        .with_ansi(ansi)
         .try_init();
 }
 
-async fn run(args: CliArgs) -> Result<()> {
+fn show_history(args: &CliArgs, limit: usize) -> Result<()> {
+    let db = args
+        .open_history()?
+        .ok_or_else(|| anyhow!("`history` requires --db PATH"))?;
+    for entry in db.recent(limit)? {
+        println!(
+            "[{}] {} status={}{}",
+            entry.timestamp,
+            entry.model,
+            entry.status,
+            if entry.challenged { " challenged" } else { "" }
+        );
+        println!("  prompt:   {}", entry.prompt);
+        println!("  response: {}", entry.response);
+    }
+    Ok(())
+}
+
+/// Lets the user choose among the known models, starting at `current`.
+fn pick_model(current: &str) -> Result<String> {
+    let labels: Vec<String> = model::models()
+        .iter()
+        .map(|info| format!("{} ({}k context)", info.id, info.context_window / 1000))
+        .collect();
+    let default = model::models()
+        .iter()
+        .position(|info| info.id == current)
+        .unwrap_or(0);
+    let choice = Select::new()
+        .with_prompt("Choose a model")
+        .items(&labels)
+        .default(default)
+        .interact()?;
+    Ok(model::models()[choice].id.to_owned())
+}
+
+async fn run(mut args: CliArgs) -> Result<()> {
+    if let Some(Command::History { limit }) = args.command {
+        return show_history(&args, limit);
+    }
+    if let Some(Command::Eval { script }) = &args.command {
+        let script = cli::read_eval_script(script)?;
+        let hashes = js::evaluate(&script, &args.user_agent(), &AtomicBool::new(false))?;
+        println!("{}", serde_json::to_string_pretty(&hashes)?);
+        return Ok(());
+    }
+
+    if args.selftest {
+        js::selftest()?;
+        println!("JS runtime self-test passed");
+        return Ok(());
+    }
+
+    if args.pick_model {
+        if std::io::stdin().is_terminal() {
+            args.model = pick_model(&args.model)?;
+        } else {
+            tracing::warn!(
+                "--pick-model needs an interactive terminal; using {}",
+                args.model
+            );
+        }
+    }
+
     let session_config = args.session_config();
     let session = session::HttpSession::new(&session_config)?;
-    let vqd = vqd::prepare_session(&session).await?;
 
-    println!("UA: {}", args.user_agent);
+    if let Some(path) = &args.cookie_file {
+        let loaded = session.load_cookies(path)?;
+        tracing::debug!(path = %path.display(), loaded, "loaded cookie file");
+    }
+
+    model::discovery::refresh(&session, args.refresh_models).await;
+
+    let result = run_session(&args, &session).await;
+    if let Some(path) = &args.cookie_file {
+        match session.save_cookies(path) {
+            Ok(()) => {}
+            // The run's own error matters more than the cookie file.
+            Err(err) if result.is_err() => tracing::warn!("failed to save cookie file: {err:#}"),
+            Err(err) => return Err(err),
+        }
+    }
+    result
+}
+
+/// Everything after session setup: VQD preparation and the requested action.
+async fn run_session(args: &CliArgs, session: &session::HttpSession) -> Result<()> {
+    if let Some(path) = &args.resume_challenge {
+        let solved = challenge::resume_challenge(session, path).await?;
+        println!("challenge solved: {solved}");
+        return Ok(());
+    }
+
+    let vqd = vqd::prepare_session(session, args.vqd_cache().as_ref()).await?;
+
+    if args.stdin_lines {
+        return run_stdin_lines(args, session, &vqd).await;
+    }
+
+    if args.bench_models {
+        let prompt = args.resolve_prompt()?;
+        let results = bench::run(session, &vqd, &prompt, &args.bench_models()).await;
+        print!("{}", bench::format_table(&results));
+        return Ok(());
+    }
+
+    println!("UA: {}", session.user_agent());
     println!("client_hashes raw: {:?}", vqd.raw_client);
     println!("client_hashes sha256: {:?}", vqd.hashed_client);
     println!("x-fe-version: {}", vqd.fe_version);
@@ -39,8 +165,33 @@ async fn run(args: CliArgs) -> Result<()> {
         return Ok(());
     }
 
-    let prompt = args.resolve_prompt()?;
-    let chat = chat::send_chat(&session, &vqd, &prompt, &args.model, None).await?;
+    let prompt = model::fit_prompt(args.resolve_prompt()?, &args.model, args.truncate_prompt)
+        .map_err(|message| anyhow!(message))?;
+    tracing::debug!(
+        model = %args.model,
+        prompt = %util::loggable(&prompt, args.redact),
+        "sending chat prompt"
+    );
+    let mut messages = args.load_history()?;
+    messages.push(chat::ChatMessage::user(prompt.as_str()));
+    let mut request = chat::ChatRequestBuilder::conversation(messages)
+        .model(args.model.as_str())
+        .tools(args.tools);
+    if let Some(system) = &args.system {
+        request = request.system(system.as_str());
+    }
+    // Opened before sending so a bad --db path cannot throw away a finished reply.
+    let history = args.open_history()?;
+    let chat = request.send(session, &vqd).await?;
+    discard_rejected_vqd(args, chat.status);
+    if let Some(db) = &history {
+        db.record(&history::HistoryEntry::new(
+            &args.model,
+            &prompt,
+            &chat,
+            args.redact,
+        ))?;
+    }
     println!("chat status: {}", chat.status);
     match chat.status {
         200 => println!("chat stream:\n{}", chat.body),
@@ -51,16 +202,100 @@ async fn run(args: CliArgs) -> Result<()> {
     Ok(())
 }
 
-#[tokio::main]
-async fn main() {
-    init_tracing();
+/// Drops the `--vqd-cache` file once upstream answers 401, or 418 left unsolved, since the
+/// cached session is what it rejected.
+fn discard_rejected_vqd(args: &CliArgs, status: u16) {
+    if matches!(status, 401 | 418) {
+        if let Some(cache) = args.vqd_cache() {
+            cache.discard();
+        }
+    }
+}
+
+/// Printed after each response in `--stdin-lines` mode.
+const RESPONSE_DELIMITER: &str = "--- end of response ---";
+
+/// Answers one prompt per STDIN line, streaming each reply followed by a delimiter.
+async fn run_stdin_lines(
+    args: &CliArgs,
+    session: &session::HttpSession,
+    vqd: &vqd::VqdSession,
+) -> Result<()> {
+    let history = args.open_history()?;
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let prompt = model::fit_prompt(line.to_owned(), &args.model, args.truncate_prompt)
+            .map_err(|message| anyhow!(message))?;
+
+        let mut request = chat::ChatRequestBuilder::new(prompt.as_str())
+            .model(args.model.as_str())
+            .tools(args.tools);
+        if let Some(system) = &args.system {
+            request = request.system(system.as_str());
+        }
+        let (text, response) = request.stream(session, vqd, args.stream_buffer());
+        let printer = text.for_each(|text| {
+            print!("{text}");
+            let _ = std::io::stdout().flush();
+            std::future::ready(())
+        });
+        let (chat, ()) = tokio::join!(response, printer);
+        let chat = chat?;
+        discard_rejected_vqd(args, chat.status);
+
+        if chat.status != 200 {
+            eprintln!("chat status {}: {}", chat.status, chat.body.trim());
+        }
+        println!("\n{RESPONSE_DELIMITER}");
+        if let Some(db) = &history {
+            db.record(&history::HistoryEntry::new(
+                &args.model,
+                &prompt,
+                &chat,
+                args.redact,
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn build_runtime(worker_threads: Option<usize>) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = worker_threads {
+        builder.worker_threads(threads);
+    }
+    builder.enable_all().build()
+}
+
+fn main() {
     let args = CliArgs::parse();
+    init_tracing(args.use_color(), args.verbose);
 
-    let result = if args.serve {
-        server::run_openai_server(&args).await
-    } else {
-        run(args).await
-    };
+    if args.print_config {
+        match serde_json::to_string_pretty(&args.effective_config()) {
+            Ok(config) => println!("{config}"),
+            Err(error) => tracing::error!("{error:?}"),
+        }
+        return;
+    }
+
+    let result = build_runtime(args.worker_threads())
+        .map_err(anyhow::Error::from)
+        .and_then(|runtime| {
+            runtime.block_on(async {
+                if args.serve {
+                    server::run_openai_server(&args).await
+                } else {
+                    run(args).await
+                }
+            })
+        });
 
     if let Err(error) = result {
         tracing::error!("{error:?}");
@@ -0,0 +1,51 @@
+//! Canned Duck.ai responses for offline development (`--mock`).
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::error::Result;
+use crate::model::EvaluatedHashes;
+use crate::vqd::VqdSession;
+
+const DEFAULT_MOCK_STREAM: &str = concat!(
+    "data: {\"role\":\"assistant\",\"message\":\"Hello\",\"action\":\"success\",\"model\":\"mock\"}\n\n",
+    "data: {\"role\":\"assistant\",\"message\":\" from the duckai-cli mock.\",\"action\":\"success\",\"model\":\"mock\"}\n\n",
+    "data: [DONE]\n\n",
+);
+
+/// Mock-mode settings carried by the session configuration.
+#[derive(Debug, Clone, Default)]
+pub struct MockConfig {
+    /// File holding the raw SSE body to replay instead of the built-in stream.
+    pub response_file: Option<PathBuf>,
+}
+
+impl MockConfig {
+    /// Loads the SSE body replayed for every chat request.
+    pub fn load_response(&self) -> Result<String> {
+        match &self.response_file {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("reading mock response file {}", path.display())),
+            None => Ok(DEFAULT_MOCK_STREAM.to_owned()),
+        }
+    }
+}
+
+/// Fixed VQD session returned by `prepare_session` in mock mode.
+pub fn vqd_session() -> VqdSession {
+    let client_hashes = vec!["mock-client".to_owned()];
+    VqdSession {
+        vqd_header: "mock-vqd-header".to_owned(),
+        fe_version: "mock-fe-version".to_owned(),
+        hashed_client: client_hashes.clone(),
+        raw_client: client_hashes.clone(),
+        eval: EvaluatedHashes {
+            client_hashes,
+            server_hashes: vec!["mock-server".to_owned()],
+            signals: serde_json::Value::Null,
+            meta: serde_json::Value::Null,
+        },
+        status_body: serde_json::json!({ "status": "mock" }),
+    }
+}
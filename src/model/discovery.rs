@@ -0,0 +1,225 @@
+//! Model list fetched from Duck.ai and cached on disk.
+//!
+//! The cache lives at `DUCKAI_MODELS_CACHE`, or `duckai-cli/models.json` under
+//! `XDG_CACHE_HOME` (falling back to `~/.cache`). It is read once at startup, before
+//! arguments are parsed, so `--model` validation sees the last fetched list. A stale or
+//! missing cache is refreshed after the session is built. Fetched ids are added to the
+//! built-in [`MODELS`](super::MODELS), never substituted for them, so the default model
+//! stays valid whatever the endpoint returns. A failed fetch is recorded in the cache as
+//! well, so the next attempt waits for the TTL instead of happening on every run.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{ModelInfo, MODELS};
+use crate::error::Result;
+use crate::session::HttpSession;
+use crate::transport::TransportRequest;
+use crate::util;
+
+/// How long a fetched list is used before the next run fetches again.
+pub const MODEL_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Context window assumed for models missing from the built-in table.
+const DEFAULT_CONTEXT_WINDOW: u32 = 32_768;
+
+/// Contents of the model cache file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelCache {
+    /// Unix seconds of the fetch.
+    pub fetched_at: u64,
+    pub models: Vec<String>,
+}
+
+impl ModelCache {
+    fn new(models: Vec<String>) -> Self {
+        Self {
+            fetched_at: util::unix_now(),
+            models,
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("reading model cache {}", path.display()))?;
+        serde_json::from_slice(&bytes)
+            .with_context(|| format!("parsing model cache {}", path.display()))
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("writing model cache {}", path.display()))
+    }
+
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        util::unix_now().saturating_sub(self.fetched_at) < ttl.as_secs()
+    }
+
+    /// The built-in models followed by any cached ids they do not already cover.
+    pub fn model_infos(&self) -> Vec<ModelInfo> {
+        let mut infos = MODELS.to_vec();
+        for id in &self.models {
+            if !infos.iter().any(|model| model.id == id) {
+                infos.push(ModelInfo {
+                    id: Box::leak(id.clone().into_boxed_str()),
+                    object: "model",
+                    created: 0,
+                    owned_by: "duck.ai",
+                    context_window: DEFAULT_CONTEXT_WINDOW,
+                });
+            }
+        }
+        infos
+    }
+}
+
+/// Location of the model cache file, if one can be determined.
+pub fn cache_path() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+    if let Some(path) = var("DUCKAI_MODELS_CACHE") {
+        return Some(PathBuf::from(path));
+    }
+    let base = var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| var("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(base.join("duckai-cli").join("models.json"))
+}
+
+/// Model list from the cache file, ignoring its age.
+pub(super) fn load_cached() -> Option<Vec<ModelInfo>> {
+    let path = cache_path()?;
+    let cache = ModelCache::read(&path).ok()?;
+    Some(cache.model_infos())
+}
+
+/// Fetches the model list when the cache is stale, missing, or `force` is set, then writes
+/// the cache and switches [`models`](super::models) to the new list. A failure is logged
+/// and stamped into the cache with the previously cached ids, so it is not retried until
+/// the TTL runs out again.
+pub async fn refresh(session: &HttpSession, force: bool) {
+    if session.mock_response().is_some() {
+        return;
+    }
+    let path = cache_path();
+    let previous = path.as_deref().and_then(|path| ModelCache::read(path).ok());
+    let fresh = previous
+        .as_ref()
+        .is_some_and(|cache| cache.is_fresh(MODEL_CACHE_TTL));
+    if fresh && !force {
+        return;
+    }
+
+    let cache = match fetch_models(session).await {
+        Ok(models) => {
+            tracing::info!(count = models.len(), "fetched the Duck.ai model list");
+            ModelCache::new(models)
+        }
+        Err(err) => {
+            tracing::warn!(error = %err, "could not fetch the model list; using the known models");
+            ModelCache::new(previous.map(|cache| cache.models).unwrap_or_default())
+        }
+    };
+    if let Some(path) = &path {
+        if let Err(err) = cache.write(path) {
+            tracing::warn!(error = %err, "could not save the model list");
+        }
+    }
+    super::set_models(cache.model_infos());
+}
+
+/// Requests the model list from Duck.ai.
+pub async fn fetch_models(session: &HttpSession) -> Result<Vec<String>> {
+    let url = session
+        .base_url()
+        .join(&session.paths().models)
+        .context("invalid models url")?;
+    let response = session
+        .transport()
+        .send(TransportRequest::get(url).header("accept", "application/json")?)
+        .await
+        .with_context(|| format!("requesting /{}", session.paths().models))?;
+    if !response.is_success() {
+        return Err(anyhow!("model list request failed: {}", response.status));
+    }
+    let body: Value = response.json().await.context("parsing model list")?;
+    let models = parse_model_list(&body);
+    if models.is_empty() {
+        return Err(anyhow!("model list response contained no model ids"));
+    }
+    Ok(models)
+}
+
+/// Extracts model ids from a list of ids or of objects with an `id`/`model` field, either
+/// at the top level or under `models`/`data`.
+pub fn parse_model_list(body: &Value) -> Vec<String> {
+    let entries = body
+        .as_array()
+        .or_else(|| body.get("models").and_then(Value::as_array))
+        .or_else(|| body.get("data").and_then(Value::as_array));
+    let mut ids: Vec<String> = Vec::new();
+    for entry in entries.into_iter().flatten() {
+        let id = entry
+            .as_str()
+            .or_else(|| entry.get("id").and_then(Value::as_str))
+            .or_else(|| entry.get("model").and_then(Value::as_str));
+        if let Some(id) = id.map(str::trim).filter(|id| !id.is_empty()) {
+            if !ids.iter().any(|known| known == id) {
+                ids.push(id.to_owned());
+            }
+        }
+    }
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::DEFAULT_MODEL_ID;
+    use serde_json::json;
+
+    #[test]
+    fn parses_the_known_list_shapes() {
+        assert_eq!(parse_model_list(&json!(["a", "b", "a"])), ["a", "b"]);
+        assert_eq!(
+            parse_model_list(&json!({"models": [{"id": "gpt-5-mini"}, {"model": "new-model"}]})),
+            ["gpt-5-mini", "new-model"]
+        );
+        assert_eq!(parse_model_list(&json!({"data": [{"id": " x "}]})), ["x"]);
+        assert!(parse_model_list(&json!({"unrelated": true})).is_empty());
+    }
+
+    #[test]
+    fn cached_ids_extend_the_built_in_models() {
+        let cache = ModelCache::new(vec!["gpt-5-mini".into(), "brand-new".into()]);
+        assert!(cache.is_fresh(MODEL_CACHE_TTL));
+        let infos = cache.model_infos();
+        assert_eq!(infos.len(), MODELS.len() + 1);
+        let default = infos
+            .iter()
+            .find(|model| model.id == DEFAULT_MODEL_ID)
+            .unwrap();
+        assert_eq!(default.context_window, 400_000);
+        let added = infos.last().unwrap();
+        assert_eq!(added.id, "brand-new");
+        assert_eq!(added.context_window, DEFAULT_CONTEXT_WINDOW);
+
+        let unrelated = ModelCache::new(vec!["some-new-model".into()]);
+        assert!(unrelated
+            .model_infos()
+            .iter()
+            .any(|model| model.id == DEFAULT_MODEL_ID));
+
+        let stale = ModelCache {
+            fetched_at: 0,
+            models: vec![],
+        };
+        assert!(!stale.is_fresh(MODEL_CACHE_TTL));
+    }
+}
@@ -1,8 +1,15 @@
 //! Data transfer object definitions will live here.
 
+use std::sync::RwLock;
+
 use clap::builder::PossibleValuesParser;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
+use crate::util::{self, TruncateSide};
+
+pub mod discovery;
+
 /// Available model definitions exposed by Duck.ai.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct ModelInfo {
@@ -10,48 +17,115 @@ pub struct ModelInfo {
     pub object: &'static str,
     pub created: u64,
     pub owned_by: &'static str,
+    /// Context window in tokens.
+    pub context_window: u32,
 }
 
+/// Built-in model list; a fetched list only ever adds to it.
 pub const MODELS: &[ModelInfo] = &[
     ModelInfo {
         id: "gpt-4o-mini",
         object: "model",
         created: 0,
         owned_by: "duck.ai",
+        context_window: 128_000,
     },
     ModelInfo {
         id: "claude-3-5-haiku-latest",
         object: "model",
         created: 0,
         owned_by: "duck.ai",
+        context_window: 200_000,
     },
     ModelInfo {
         id: "mistralai/Mistral-Small-24B-Instruct-2501",
         object: "model",
         created: 0,
         owned_by: "duck.ai",
+        context_window: 32_768,
     },
     ModelInfo {
         id: "gpt-5-mini",
         object: "model",
         created: 0,
         owned_by: "duck.ai",
+        context_window: 400_000,
     },
     ModelInfo {
         id: "openai/gpt-oss-120b",
         object: "model",
         created: 0,
         owned_by: "duck.ai",
+        context_window: 131_072,
     },
 ];
 
 pub const DEFAULT_MODEL_ID: &str = "gpt-5-mini";
+
+/// Current model list. Tests always see the built-in list, never a user's cache.
+static CURRENT_MODELS: Lazy<RwLock<&'static [ModelInfo]>> = Lazy::new(|| {
+    let cached = if cfg!(test) {
+        None
+    } else {
+        discovery::load_cached()
+    };
+    RwLock::new(cached.map_or(MODELS, |models| Box::leak(models.into_boxed_slice())))
+});
+
+/// Models currently known: [`MODELS`] plus any extra ids from the cached Duck.ai list.
+pub fn models() -> &'static [ModelInfo] {
+    *CURRENT_MODELS.read().expect("model list lock poisoned")
+}
+
+/// Replaces the model list after a refresh. Lists are leaked; this happens at most once
+/// per run.
+fn set_models(models: Vec<ModelInfo>) {
+    *CURRENT_MODELS.write().expect("model list lock poisoned") =
+        Box::leak(models.into_boxed_slice());
+}
+
 /// Build a Clap value parser that restricts input to the known model identifiers.
 pub fn model_value_parser() -> PossibleValuesParser {
-    let values: Vec<&'static str> = MODELS.iter().map(|model| model.id).collect();
+    let values: Vec<&'static str> = models().iter().map(|model| model.id).collect();
     PossibleValuesParser::new(values)
 }
 
+/// Checks a rendered prompt against the model's context window (estimated in characters).
+///
+/// Oversized prompts are an error unless `truncate` names the side to trim.
+pub fn fit_prompt(
+    prompt: String,
+    model_id: &str,
+    truncate: Option<TruncateSide>,
+) -> Result<String, String> {
+    let Some(model) = models().iter().find(|model| model.id == model_id) else {
+        return Ok(prompt);
+    };
+    let max_chars = model.context_window as usize * util::CHARS_PER_TOKEN;
+    let chars = prompt.chars().count();
+    if chars <= max_chars {
+        return Ok(prompt);
+    }
+
+    match truncate {
+        Some(side) => {
+            let (trimmed, dropped) = util::trim_to_chars(&prompt, max_chars, side);
+            tracing::warn!(
+                model = model_id,
+                dropped,
+                side = ?side,
+                "prompt exceeded context window; truncated"
+            );
+            Ok(trimmed)
+        }
+        None => Err(format!(
+            "prompt is about {} tokens but `{model_id}` accepts {}; shorten it or use --truncate-prompt",
+            chars / util::CHARS_PER_TOKEN,
+            model.context_window
+        )),
+    }
+}
+
 /// Raw status payload from `/duckchat/v1/status`.
 pub type StatusResponse = serde_json::Value;
 
@@ -65,3 +139,25 @@ pub struct EvaluatedHashes {
     #[serde(default)]
     pub meta: serde_json::Value,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_prompt_errors_unless_truncation_is_requested() {
+        let limit = 32_768 * util::CHARS_PER_TOKEN;
+        let model = "mistralai/Mistral-Small-24B-Instruct-2501";
+        let prompt = "x".repeat(limit + 10);
+
+        let err = fit_prompt(prompt.clone(), model, None).unwrap_err();
+        assert!(err.contains("--truncate-prompt"));
+
+        let trimmed = fit_prompt(prompt, model, Some(TruncateSide::Head)).unwrap();
+        assert_eq!(trimmed.chars().count(), limit);
+        assert_eq!(
+            fit_prompt("short".to_owned(), model, None).unwrap(),
+            "short"
+        );
+    }
+}
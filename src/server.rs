@@ -1,46 +1,188 @@
 use std::{
-    collections::HashSet,
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
     convert::Infallible,
     net::SocketAddr,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context as AnyhowContext};
 use axum::{
     debug_handler,
-    extract::{Path, State},
-    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    extract::{Path, Query, State},
+    http::{
+        header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
     response::{
-        sse::{Event, Sse},
+        sse::{Event, KeepAlive, Sse},
         IntoResponse, Response,
     },
-    routing::{get, post},
+    routing::{get, post, MethodRouter},
     Json, Router,
 };
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
 use tokio::{net::TcpListener, signal, sync::mpsc};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use uuid::Uuid;
 
 use crate::{
-    chat,
+    cache::SessionCache,
+    chat::{self, ChatMessage, ChatResponse, ChatRole},
     cli::CliArgs,
     error::Result,
+    history::{HistoryDb, HistoryEntry},
+    json_capture::JsonCapture,
     model,
-    session::{HttpSession, SessionConfig},
-    vqd,
+    session::HttpSession,
+    stats::ServerStats,
+    util,
+    vqd::{self, VqdSession},
 };
 
-const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8080";
+pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8080";
+/// Role reported for completions unless `--response-role` overrides it.
+pub const DEFAULT_RESPONSE_ROLE: &str = "assistant";
 
 #[derive(Clone)]
 struct ServerState {
-    session_config: SessionConfig,
+    sessions: Arc<SessionCache>,
     default_model: String,
     auth_header: Option<String>,
+    admin_auth_header: Option<String>,
     allowed_models: Arc<HashSet<&'static str>>,
+    redact: bool,
+    stream_buffer: usize,
+    logprobs_mode: LogprobsMode,
+    on_empty: OnEmpty,
+    trim_output: TrimOutput,
+    include_citations: bool,
+    debug_echo_prompt: bool,
+    response_role: Arc<str>,
+    expose_vqd_debug: bool,
+    error_detail: ErrorDetail,
+    strict: bool,
+    truncate_prompt: Option<util::TruncateSide>,
+    api_version: Arc<str>,
+    history: Option<Arc<HistoryDb>>,
+    stream_fallback_json: bool,
+    max_messages: Option<usize>,
+    response_content_array: bool,
+    eager_first_chunk: bool,
+    done_marker: DoneMarker,
+    strip_patterns: Arc<[Regex]>,
+    stats: Arc<ServerStats>,
+    sse_send_timeout: Option<Duration>,
+    /// Ceiling for `X-DuckAI-Timeout` overrides.
+    max_request_timeout: Duration,
+    stream_debug: bool,
+    max_message_chars: Option<usize>,
+    context_budget: Option<usize>,
+    completion_ids: Arc<CompletionIds>,
+}
+
+impl ServerState {
+    /// Per-server settings taken from the command line.
+    fn new(
+        args: &CliArgs,
+        sessions: Arc<SessionCache>,
+        allowed_models: HashSet<&'static str>,
+    ) -> Result<Self> {
+        let auth_header = args
+            .server_api_key
+            .as_ref()
+            .map(|key| format!("Bearer {key}"));
+        let admin_auth_header = args
+            .admin_api_key
+            .as_ref()
+            .map(|key| format!("Bearer {key}"));
+        Ok(Self {
+            sessions,
+            default_model: args.model.clone(),
+            auth_header,
+            admin_auth_header,
+            allowed_models: Arc::new(allowed_models),
+            redact: args.redact,
+            stream_buffer: args.stream_buffer(),
+            logprobs_mode: args.logprobs_mode,
+            on_empty: args.on_empty,
+            trim_output: args.trim_output,
+            include_citations: args.include_citations,
+            debug_echo_prompt: args.debug_echo_prompt,
+            response_role: Arc::from(args.response_role.as_str()),
+            expose_vqd_debug: args.expose_vqd_debug,
+            error_detail: args.error_detail,
+            strict: args.strict,
+            truncate_prompt: args.truncate_prompt,
+            api_version: Arc::from(args.api_version.as_str()),
+            history: args.open_history()?.map(Arc::new),
+            stream_fallback_json: args.stream_fallback_json,
+            max_messages: args.max_messages,
+            response_content_array: args.response_content_array,
+            eager_first_chunk: args.eager_first_chunk,
+            done_marker: args.done_marker,
+            strip_patterns: Arc::from(args.strip_patterns.as_slice()),
+            stats: Arc::new(ServerStats::new()),
+            sse_send_timeout: args.sse_send_timeout(),
+            max_request_timeout: args.max_request_timeout(),
+            stream_debug: args.stream_debug,
+            max_message_chars: args.max_message_chars,
+            context_budget: args.context_budget,
+            completion_ids: Arc::new(CompletionIds::new(args.deterministic_ids)),
+        })
+    }
+
+    /// The shared HTTP session and its VQD metadata. Prepared on first use and again after
+    /// expiry or a rejection; concurrent callers wait on the same preparation.
+    async fn session_and_vqd(&self) -> Result<(Arc<HttpSession>, Arc<VqdSession>)> {
+        let cached = self.sessions.get().await?;
+        Ok((cached.session, cached.vqd))
+    }
+
+    /// Discards the shared session when upstream rejected it (401, or 418 left unsolved by
+    /// challenge handling), so the next request prepares a new one.
+    async fn discard_if_rejected(&self, vqd: &Arc<VqdSession>, status: u16) {
+        if matches!(status, 401 | 418) && self.sessions.invalidate(vqd).await {
+            // The memoised fe-version may be what upstream objected to.
+            vqd::expire_fe_version();
+            tracing::warn!(
+                status,
+                "upstream rejected the VQD session; it will be prepared again"
+            );
+        }
+    }
+}
+
+/// Source of `chatcmpl-` ids. `--deterministic-ids` swaps random UUIDs for a counter so
+/// responses can be snapshot-tested.
+#[derive(Debug)]
+struct CompletionIds {
+    sequence: Option<AtomicU64>,
+}
+
+impl CompletionIds {
+    fn new(deterministic: bool) -> Self {
+        Self {
+            sequence: deterministic.then(|| AtomicU64::new(0)),
+        }
+    }
+
+    fn next(&self) -> String {
+        let uuid = match &self.sequence {
+            Some(sequence) => {
+                Uuid::from_u128(u128::from(sequence.fetch_add(1, Ordering::Relaxed)) + 1)
+            }
+            None => Uuid::new_v4(),
+        };
+        format!("chatcmpl-{uuid}")
+    }
 }
 
 type SharedState = ServerState;
@@ -54,41 +196,46 @@ pub async fn run_openai_server(args: &CliArgs) -> Result<()> {
         .parse()
         .with_context(|| format!("parsing listen address `{listen}`"))?;
 
-    let session_config = args.session_config();
-    let default_model = args.model.clone();
-    let auth_header = args
-        .server_api_key
-        .as_ref()
-        .map(|key| format!("Bearer {key}"));
-    let allowed_models: HashSet<&'static str> = model::MODELS.iter().map(|m| m.id).collect();
-
-    let state = ServerState {
-        session_config,
-        default_model,
-        auth_header,
-        allowed_models: Arc::new(allowed_models),
-    };
+    crate::js::selftest()?;
+    let sessions = SessionCache::new(args.session_config(), args.vqd_ttl());
+    model::discovery::refresh(
+        &crate::session::HttpSession::new(sessions.config())?,
+        args.refresh_models,
+    )
+    .await;
+    let allowed_models: HashSet<&'static str> = model::models().iter().map(|m| m.id).collect();
+    check_allowed_models(&allowed_models)?;
+
+    let sessions = Arc::new(sessions);
+    if let Some(timeout) = args.wait_for_upstream() {
+        wait_for_upstream(&sessions, timeout).await?;
+    }
+    let state = ServerState::new(args, sessions.clone(), allowed_models)?;
 
-    let router = Router::new()
-        .route("/v1/models", get(list_models))
-        .route("/v1/models/:model_id", get(get_model))
-        .route("/v1/chat/completions", post(chat_completions))
-        .with_state(state);
+    let router = routes().with_state(state.clone());
 
-    let listener = TcpListener::bind(addr)
-        .await
+    let listener = bind_listener(addr, args.tcp_keepalive())
         .context("binding OpenAI-compatible server address")?;
-    println!(
-        "OpenAI-compatible service listening on http://{}",
-        listener.local_addr().unwrap_or(addr)
-    );
+    let bound = listener.local_addr().unwrap_or(addr);
+    tracing::info!("OpenAI-compatible service listening on http://{bound}");
+    log_startup_summary(&state, args, bound);
+
+    let js_cancel = sessions.config().js_cancel.clone();
+    if args.warm_standby {
+        tokio::spawn(sessions.clone().keep_standby_warm());
+    }
+    if args.warmup {
+        tokio::spawn(warm_up(sessions));
+    }
 
     axum::serve(listener, router)
-        .with_graceful_shutdown(async {
+        .tcp_nodelay(args.tcp_nodelay)
+        .with_graceful_shutdown(async move {
             if let Err(err) = signal::ctrl_c().await {
                 tracing::warn!("failed to listen for shutdown signal: {err:?}");
             }
-            println!("Shutdown signal received; stopping server…");
+            tracing::info!("Shutdown signal received; stopping server…");
+            js_cancel.store(true, Ordering::SeqCst);
         })
         .await
         .context("running OpenAI-compatible server")?;
@@ -96,6 +243,189 @@ pub async fn run_openai_server(args: &CliArgs) -> Result<()> {
     Ok(())
 }
 
+/// Binds the server socket; keep-alive set on the listener is inherited by accepted connections.
+fn bind_listener(addr: SocketAddr, keepalive: Option<Duration>) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if let Some(idle) = keepalive {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Logs the effective server settings as structured fields.
+fn log_startup_summary(state: &ServerState, args: &CliArgs, bound: SocketAddr) {
+    let mut allowed_models: Vec<&str> = state.allowed_models.iter().copied().collect();
+    allowed_models.sort_unstable();
+    tracing::info!(
+        address = %bound,
+        auth = state.auth_header.is_some(),
+        admin_auth = state.admin_auth_header.is_some(),
+        default_model = %state.default_model,
+        allowed_models = %allowed_models.join(","),
+        vqd_ttl_secs = args.vqd_ttl().as_secs(),
+        warmup = args.warmup,
+        warm_standby = args.warm_standby,
+        proxy = if args.proxy.is_some() {
+            "--proxy"
+        } else {
+            proxy_source().unwrap_or("none")
+        },
+        "server configuration"
+    );
+}
+
+/// Name of the proxy environment variable reqwest picks up without `--proxy`, if any. The
+/// value itself is not reported since it may carry credentials.
+fn proxy_source() -> Option<&'static str> {
+    [
+        "HTTPS_PROXY",
+        "https_proxy",
+        "HTTP_PROXY",
+        "http_proxy",
+        "ALL_PROXY",
+        "all_proxy",
+    ]
+    .into_iter()
+    .find(|name| std::env::var_os(name).is_some_and(|value| !value.is_empty()))
+}
+
+/// Refuses to start when no model would be accepted, rather than rejecting every request.
+fn check_allowed_models(allowed: &HashSet<&'static str>) -> Result<()> {
+    if allowed.is_empty() {
+        let valid: Vec<&str> = model::models().iter().map(|m| m.id).collect();
+        return Err(anyhow!(
+            "no models are allowed; valid model ids: {}",
+            valid.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Longest pause between `--wait-for-upstream` attempts.
+const MAX_UPSTREAM_WAIT_DELAY: Duration = Duration::from_secs(10);
+
+/// Retries VQD preparation until it succeeds or `timeout` elapses, so the server can start
+/// before Duck.ai is reachable (e.g. while container networking comes up).
+async fn wait_for_upstream(sessions: &SessionCache, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut delay = Duration::from_secs(1);
+    loop {
+        let err = match sessions.get().await {
+            Ok(_) => {
+                tracing::info!("upstream reachable; VQD session prepared");
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(err.context(format!(
+                "upstream still unreachable after waiting {}s",
+                timeout.as_secs()
+            )));
+        }
+        tracing::warn!(
+            retry_in_ms = delay.min(remaining).as_millis() as u64,
+            "waiting for upstream: {err:#}"
+        );
+        tokio::time::sleep(delay.min(remaining)).await;
+        delay = (delay * 2).min(MAX_UPSTREAM_WAIT_DELAY);
+    }
+}
+
+/// Prepares the cached VQD session ahead of the first request.
+async fn warm_up(sessions: Arc<SessionCache>) {
+    let started = Instant::now();
+    match sessions.get().await {
+        Ok(_) => tracing::info!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "VQD session warmed up"
+        ),
+        Err(err) => tracing::warn!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "VQD warmup failed; sessions will be prepared on demand: {err:#}"
+        ),
+    }
+}
+
+/// How `logprobs` requests are answered, since Duck.ai exposes no token probabilities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogprobsMode {
+    /// Reject requests asking for logprobs with a 400.
+    Error,
+    /// Return an empty `{"content": []}` logprobs object.
+    Empty,
+    /// Return `logprobs: null` (OpenAI's shape when logprobs are not requested).
+    Null,
+}
+
+/// Terminator sent at the end of a streamed completion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DoneMarker {
+    /// `data: [DONE]`, as OpenAI sends it.
+    Standard,
+    /// End the stream without a terminator event.
+    Omit,
+    /// `data: {"done":true}` for clients that only accept JSON events.
+    Json,
+}
+
+impl DoneMarker {
+    fn payload(self) -> Option<String> {
+        match self {
+            Self::Standard => Some("[DONE]".to_owned()),
+            Self::Omit => None,
+            Self::Json => Some(json!({ "done": true }).to_string()),
+        }
+    }
+}
+
+/// What to do when Duck.ai finishes a response without any content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnEmpty {
+    /// Send the request once more, then fail if it is still empty.
+    Retry,
+    /// Fail with a 502 upstream error.
+    Error,
+    /// Return the empty completion with `finish_reason: "stop"`.
+    Empty,
+}
+
+/// Whitespace trimmed from the completion text (`--trim-output`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TrimOutput {
+    /// Keep the text exactly as Duck.ai produced it.
+    None,
+    /// Drop trailing whitespace only.
+    Trailing,
+    /// Drop leading and trailing whitespace.
+    Both,
+}
+
+impl TrimOutput {
+    fn apply(self, text: &str) -> &str {
+        match self {
+            Self::None => text,
+            Self::Trailing => text.trim_end(),
+            Self::Both => text.trim(),
+        }
+    }
+}
+
+/// How much of an upstream error body is passed on to API clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorDetail {
+    /// Report only the upstream status, or a generic message for other stream failures;
+    /// the details are kept in server logs.
+    Minimal,
+    /// Include the (truncated) upstream body or the full error chain in the message.
+    Full,
+}
+
 type ApiResult<T> = std::result::Result<T, ApiError>;
 
 #[derive(Debug, Serialize)]
@@ -112,6 +442,22 @@ struct ApiErrorDetail {
     code: Option<String>,
 }
 
+/// Error reported by Duck.ai, either as an `error` action mid-stream or as a non-200 reply.
+#[derive(Debug, Clone)]
+struct UpstreamErrorEvent {
+    message: String,
+    /// Duck.ai's `type`, e.g. `ERR_CONVERSATION_LIMIT`.
+    kind: Option<String>,
+}
+
+impl std::fmt::Display for UpstreamErrorEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for UpstreamErrorEvent {}
+
 struct ApiError {
     status: StatusCode,
     body: ApiErrorBody,
@@ -136,10 +482,20 @@ impl ApiError {
         Self::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
     }
 
+    fn invalid_param(param: &str, message: impl Into<String>) -> Self {
+        let mut error = Self::bad_request(message);
+        error.body.error.param = Some(param.to_owned());
+        error
+    }
+
     fn unauthorized(message: impl Into<String>) -> Self {
         Self::new(StatusCode::UNAUTHORIZED, "authentication_error", message)
     }
 
+    fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "permission_error", message)
+    }
+
     fn not_found(message: impl Into<String>) -> Self {
         Self::new(StatusCode::NOT_FOUND, "not_found_error", message)
     }
@@ -150,15 +506,27 @@ impl ApiError {
         Self::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", message)
     }
 
-    fn upstream(status: u16, body: String) -> Self {
-        let status_code = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
-        let truncated = body.chars().take(5000).collect::<String>();
-        tracing::warn!(
-            "upstream duck.ai error status={} body_len={} snippet={}",
-            status,
-            body.len(),
-            truncated
+    fn empty_completion() -> Self {
+        Self::new(
+            StatusCode::BAD_GATEWAY,
+            "upstream_error",
+            "upstream returned an empty completion",
+        )
+    }
+
+    /// An `{"action":"error"}` event inside a 200 response; its `type` becomes the code.
+    fn upstream_event(event: &UpstreamErrorEvent) -> Self {
+        let mut error = Self::new(
+            StatusCode::BAD_GATEWAY,
+            "upstream_error",
+            format!("upstream reported an error: {}", event.message),
         );
+        error.body.error.code = event.kind.clone();
+        error
+    }
+
+    fn upstream(status: u16, body: String, detail: ErrorDetail) -> Self {
+        let status_code = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
         Self::new(
             if status_code.is_client_error() {
                 StatusCode::BAD_REQUEST
@@ -166,23 +534,134 @@ impl ApiError {
                 StatusCode::BAD_GATEWAY
             },
             "upstream_error",
-            format!("Upstream duck.ai error (status {status}): {truncated}"),
+            upstream_error_message(status, &body, detail),
         )
     }
 }
 
+/// Duck.ai's error `type` from a JSON error body, e.g. `ERR_CONVERSATION_LIMIT`.
+fn upstream_error_type(body: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    value.get("type")?.as_str().map(str::to_owned)
+}
+
+/// Logs the full upstream error and returns the message that may be shown to clients.
+fn upstream_error_message(status: u16, body: &str, detail: ErrorDetail) -> String {
+    let truncated = body.chars().take(5000).collect::<String>();
+    tracing::warn!(
+        "upstream duck.ai error status={} body_len={} snippet={}",
+        status,
+        body.len(),
+        truncated
+    );
+    match detail {
+        ErrorDetail::Minimal => format!("Upstream duck.ai error (status {status})"),
+        ErrorDetail::Full => format!("Upstream duck.ai error (status {status}): {truncated}"),
+    }
+}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         (self.status, Json(self.body)).into_response()
     }
 }
 
-async fn list_models(State(state): State<SharedState>, headers: HeaderMap) -> Response {
+/// A served endpoint as `(method, path, handler)`.
+type Endpoint = (
+    &'static str,
+    &'static str,
+    fn() -> MethodRouter<SharedState>,
+);
+
+/// The router is built from this table, so `/v1/version` always reports what is served.
+static ENDPOINTS: &[Endpoint] = &[
+    ("GET", "/v1/version", || get(version)),
+    ("GET", "/v1/models", || get(list_models)),
+    ("GET", "/v1/models/:model_id", || get(get_model)),
+    ("POST", "/v1/chat/completions", || post(chat_completions)),
+    ("POST", "/admin/rotate", || post(rotate_session)),
+    ("GET", "/admin/stats", || get(server_stats)),
+];
+
+fn routes() -> Router<SharedState> {
+    ENDPOINTS
+        .iter()
+        .fold(Router::new(), |router, (_, path, handler)| {
+            router.route(path, handler())
+        })
+}
+
+/// Unauthenticated capability report for orchestration tooling.
+async fn version(State(state): State<SharedState>) -> Response {
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "duckai_api_version": &*state.api_version,
+        "endpoints": ENDPOINTS
+            .iter()
+            .map(|(method, path, _)| format!("{method} {path}"))
+            .collect::<Vec<_>>(),
+        "features": {
+            "streaming": true,
+            "tools": false,
+            "cache": true,
+            "strict": state.strict,
+        },
+    }))
+    .into_response()
+}
+
+/// `?pretty=true` query flag for endpoints that can pretty-print their JSON.
+#[derive(Debug, Default, Deserialize)]
+struct PrettyQuery {
+    #[serde(default)]
+    pretty: bool,
+}
+
+/// Whether the client asked for indented JSON via `?pretty=true` or `X-Pretty: true`.
+fn wants_pretty(query: &PrettyQuery, headers: &HeaderMap) -> bool {
+    query.pretty
+        || headers
+            .get("x-pretty")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// JSON response body, optionally pretty-printed for eyeballing with curl.
+struct JsonBody<T> {
+    value: T,
+    pretty: bool,
+}
+
+impl<T: Serialize> IntoResponse for JsonBody<T> {
+    fn into_response(self) -> Response {
+        let rendered = if self.pretty {
+            serde_json::to_string_pretty(&self.value)
+        } else {
+            serde_json::to_string(&self.value)
+        };
+        match rendered {
+            Ok(body) => (
+                [(CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+                body,
+            )
+                .into_response(),
+            Err(err) => {
+                ApiError::internal(format!("failed to serialize response: {err}")).into_response()
+            }
+        }
+    }
+}
+
+async fn list_models(
+    State(state): State<SharedState>,
+    Query(query): Query<PrettyQuery>,
+    headers: HeaderMap,
+) -> Response {
     if let Err(err) = authorize(&state, &headers) {
         return err.into_response();
     }
 
-    let data: Vec<Value> = model::MODELS
+    let data: Vec<Value> = model::models()
         .iter()
         .map(|m| {
             json!({
@@ -194,15 +673,19 @@ async fn list_models(State(state): State<SharedState>, headers: HeaderMap) -> Re
         })
         .collect();
 
-    Json(json!({
-        "object": "list",
-        "data": data,
-    }))
+    JsonBody {
+        value: json!({
+            "object": "list",
+            "data": data,
+        }),
+        pretty: wants_pretty(&query, &headers),
+    }
     .into_response()
 }
 
 async fn get_model(
     State(state): State<SharedState>,
+    Query(query): Query<PrettyQuery>,
     headers: HeaderMap,
     Path(model_id): Path<String>,
 ) -> Response {
@@ -210,31 +693,372 @@ async fn get_model(
         return err.into_response();
     }
 
-    match model::MODELS.iter().find(|m| m.id == model_id) {
-        Some(model) => Json(json!({
-            "id": model.id,
-            "object": model.object,
-            "created": model.created,
-            "owned_by": model.owned_by,
-        }))
+    match model::models().iter().find(|m| m.id == model_id) {
+        Some(model) => JsonBody {
+            value: json!({
+                "id": model.id,
+                "object": model.object,
+                "created": model.created,
+                "owned_by": model.owned_by,
+            }),
+            pretty: wants_pretty(&query, &headers),
+        }
         .into_response(),
         None => ApiError::not_found(format!("Unknown model `{model_id}`")).into_response(),
     }
 }
 
+async fn rotate_session(State(state): State<SharedState>, headers: HeaderMap) -> Response {
+    if let Err(err) = authorize_admin(&state, &headers) {
+        return err.into_response();
+    }
+
+    match state.sessions.rotate().await {
+        Ok(cached) => {
+            tracing::info!(fe_version = %cached.vqd.fe_version, "VQD session rotated");
+            Json(json!({
+                "rotated": true,
+                "fe_version": cached.vqd.fe_version,
+                "prepared_at": util::unix_now(),
+            }))
+            .into_response()
+        }
+        Err(err) => {
+            ApiError::internal(format!("failed to prepare VQD session: {err}")).into_response()
+        }
+    }
+}
+
+async fn server_stats(State(state): State<SharedState>, headers: HeaderMap) -> Response {
+    if let Err(err) = authorize_admin(&state, &headers) {
+        return err.into_response();
+    }
+
+    let cache = state.sessions.stats();
+    let vqd_age = state.sessions.age().await;
+    Json(json!({
+        "uptime_secs": state.stats.uptime().as_secs(),
+        "total_requests": state.stats.requests(),
+        "active_streams": state.stats.active_streams(),
+        "cache": {
+            "hits": cache.hits,
+            "misses": cache.misses,
+        },
+        "vqd_cache_age_secs": vqd_age.map(|age| age.as_secs()),
+        "challenges": state.stats.challenges(),
+    }))
+    .into_response()
+}
+
 #[derive(Debug, Deserialize)]
 struct ChatCompletionRequest {
     model: Option<String>,
     messages: Vec<IncomingMessage>,
     #[serde(default)]
     stream: bool,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    n: Option<u32>,
+    max_tokens: Option<u32>,
+    max_completion_tokens: Option<u32>,
+    #[serde(default)]
+    logprobs: bool,
+    logit_bias: Option<BTreeMap<String, f64>>,
+    /// Accepted for compatibility; without upstream tool calling there is nothing to run
+    /// in parallel.
+    #[serde(rename = "parallel_tool_calls")]
+    _parallel_tool_calls: Option<bool>,
+    response_format: Option<ResponseFormat>,
+    /// Upstream timeout requested through the `X-DuckAI-Timeout` header.
+    #[serde(skip)]
+    upstream_timeout: Option<Duration>,
+    /// Fields this server does not recognise; rejected in `--strict` mode.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Value>,
+}
+
+/// OpenAI `response_format`; only its `type` is used.
+#[derive(Debug, Deserialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+impl ChatCompletionRequest {
+    /// Whether the client asked for a single JSON value (`json_object` or `json_schema`).
+    /// The schema itself is not enforced.
+    fn wants_json(&self) -> bool {
+        self.response_format
+            .as_ref()
+            .is_some_and(|format| matches!(format.kind.as_str(), "json_object" | "json_schema"))
+    }
+
+    /// Rejects conversations with more than `max` non-system messages (`--max-messages`).
+    fn check_message_limit(&self, max: usize) -> ApiResult<()> {
+        let turns = self
+            .messages
+            .iter()
+            .filter(|message| !matches!(message.role.as_str(), "system" | "developer"))
+            .count();
+        if turns > max {
+            return Err(ApiError::invalid_param(
+                "messages",
+                format!("conversation has {turns} messages but this server accepts at most {max} (system and developer messages excluded)"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Rejects requests carrying fields the server would otherwise silently ignore.
+    fn reject_unknown_fields(&self) -> ApiResult<()> {
+        let mut unknown = self.unknown.keys().cloned().collect::<Vec<_>>();
+        for (index, message) in self.messages.iter().enumerate() {
+            unknown.extend(
+                message
+                    .unknown
+                    .keys()
+                    .map(|field| format!("messages[{index}].{field}")),
+            );
+        }
+
+        match unknown.first() {
+            None => Ok(()),
+            Some(first) => Err(ApiError::invalid_param(
+                first,
+                format!("unrecognized request field(s): {}", unknown.join(", ")),
+            )),
+        }
+    }
+
+    /// Checks sampling parameters against the ranges accepted by OpenAI.
+    fn validate(&self) -> ApiResult<()> {
+        if self.messages.is_empty() {
+            return Err(ApiError::invalid_param(
+                "messages",
+                "messages array must not be empty",
+            ));
+        }
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(ApiError::invalid_param(
+                    "temperature",
+                    format!("temperature must be between 0 and 2, got {temperature}"),
+                ));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(ApiError::invalid_param(
+                    "top_p",
+                    format!("top_p must be between 0 and 1, got {top_p}"),
+                ));
+            }
+        }
+        if self.n == Some(0) {
+            return Err(ApiError::invalid_param("n", "n must be at least 1"));
+        }
+        if self.max_tokens == Some(0) {
+            return Err(ApiError::invalid_param(
+                "max_tokens",
+                "max_tokens must be greater than 0",
+            ));
+        }
+        if self.max_completion_tokens == Some(0) {
+            return Err(ApiError::invalid_param(
+                "max_completion_tokens",
+                "max_completion_tokens must be greater than 0",
+            ));
+        }
+        if let Some(format) = &self.response_format {
+            if !matches!(format.kind.as_str(), "text" | "json_object" | "json_schema") {
+                return Err(ApiError::invalid_param(
+                    "response_format",
+                    format!("unsupported response_format type `{}`", format.kind),
+                ));
+            }
+        }
+        for (key, bias) in self.logit_bias.iter().flatten() {
+            if !(-100.0..=100.0).contains(bias) {
+                return Err(ApiError::invalid_param(
+                    "logit_bias",
+                    format!("logit_bias for `{key}` must be between -100 and 100, got {bias}"),
+                ));
+            }
+            // A ban the client relies on must not be silently dropped.
+            if *bias <= -100.0 && key.parse::<u64>().is_ok() {
+                return Err(ApiError::invalid_param(
+                    "logit_bias",
+                    format!(
+                        "cannot ban token id {key}: Duck.ai has no token biases and token ids \
+                         cannot be decoded here; use the literal string as the key instead"
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Best-effort `logit_bias` emulation. Duck.ai ignores token biases, so literal
+    /// (non-numeric) keys biased to -100 are honoured by removing those strings from the
+    /// output. Banned token ids are rejected by [`Self::validate`].
+    fn banned_patterns(&self) -> Vec<Regex> {
+        let mut patterns = Vec::new();
+        for (key, bias) in self.logit_bias.iter().flatten() {
+            if *bias > -100.0 || key.parse::<u64>().is_ok() {
+                continue;
+            }
+            if let Ok(pattern) = Regex::new(&regex::escape(key)) {
+                patterns.push(pattern);
+            }
+        }
+        patterns
+    }
+
+    /// Trims every message to `max_chars` characters plus an ellipsis (`--max-message-chars`),
+    /// reporting whether any message was shortened.
+    fn cap_message_lengths(&mut self, max_chars: usize) -> bool {
+        let mut truncated = false;
+        for message in &mut self.messages {
+            let text = message.text();
+            if let (kept, true) = util::truncate_chars(&text, max_chars) {
+                message.content = ChatMessageContent::Text(format!("{kept}…"));
+                message.tool_calls = None;
+                truncated = true;
+            }
+        }
+        truncated
+    }
+
+    /// Drops the oldest turns until the estimated prompt size fits `budget` tokens
+    /// (`--context-budget`). A turn is a user message with the assistant replies and tool
+    /// results after it, so a reply never outlives its question and a tool result never
+    /// outlives its call; assistant or tool messages before the first user message go first.
+    /// System and developer messages and the latest user turn are always kept. Sizes include
+    /// the role labels and separators [`render_conversation`] adds. Returns the number of
+    /// turns removed.
+    fn fit_context_budget(&mut self, budget: usize) -> usize {
+        let tokens = |message: &IncomingMessage| {
+            let text = message.text();
+            if text.is_empty() {
+                return 0;
+            }
+            // "Label: text" plus the blank line joining it to the next section.
+            (message.label().chars().count() + 2 + text.chars().count() + 2)
+                .div_ceil(util::CHARS_PER_TOKEN)
+        };
+        let mut total: usize = self.messages.iter().map(tokens).sum();
+        let last_user = self
+            .messages
+            .iter()
+            .rposition(|message| message.role == "user")
+            .unwrap_or(self.messages.len());
+
+        let mut turns: Vec<Vec<usize>> = Vec::new();
+        for (index, message) in self.messages[..last_user].iter().enumerate() {
+            if matches!(message.role.as_str(), "system" | "developer") {
+                continue;
+            }
+            match turns.last_mut() {
+                Some(turn) if message.role != "user" => turn.push(index),
+                _ => turns.push(vec![index]),
+            }
+        }
+
+        let mut keep = vec![true; self.messages.len()];
+        let mut dropped = 0;
+        for turn in turns {
+            if total <= budget {
+                break;
+            }
+            for index in turn {
+                keep[index] = false;
+                total -= tokens(&self.messages[index]);
+            }
+            dropped += 1;
+        }
+
+        let mut keep = keep.into_iter();
+        self.messages.retain(|_| keep.next().unwrap_or(true));
+        dropped
+    }
+
+    /// Completion token limit, preferring the newer `max_completion_tokens` field.
+    fn completion_token_limit(&self) -> Option<usize> {
+        self.max_completion_tokens
+            .or(self.max_tokens)
+            .map(|limit| limit as usize)
+    }
+
+    /// Resolves the `logprobs` value reported on each choice.
+    fn logprobs_value(&self, mode: LogprobsMode) -> ApiResult<Value> {
+        if !self.logprobs {
+            return Ok(Value::Null);
+        }
+        match mode {
+            LogprobsMode::Error => Err(ApiError::invalid_param(
+                "logprobs",
+                "logprobs are not supported by duck.ai",
+            )),
+            LogprobsMode::Empty => Ok(json!({ "content": [] })),
+            LogprobsMode::Null => Ok(Value::Null),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct IncomingMessage {
     role: String,
-    #[serde(default)]
+    /// `null` for assistant turns that only carry tool calls.
+    #[serde(default, deserialize_with = "null_as_default")]
     content: ChatMessageContent,
+    /// Tool calls requested by an earlier assistant turn.
+    tool_calls: Option<Vec<Value>>,
+    /// The call a `tool` message answers.
+    tool_call_id: Option<String>,
+    name: Option<String>,
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Value>,
+}
+
+fn null_as_default<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
+impl IncomingMessage {
+    /// Message text sent upstream. DuckAI has no tool calling, so assistant tool calls are
+    /// spelled out in the text to keep a tool-using conversation readable.
+    fn text(&self) -> String {
+        let mut text = self.content.render();
+        for call in self.tool_calls.iter().flatten() {
+            let function = &call["function"];
+            let name = function["name"].as_str().unwrap_or("unknown");
+            let arguments = function["arguments"].as_str().unwrap_or("{}");
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&format!("[tool call {name}({arguments})]"));
+        }
+        text
+    }
+
+    fn label(&self) -> Cow<'_, str> {
+        match self.role.as_str() {
+            "system" => "System".into(),
+            // OpenAI's newer name for system-level instructions.
+            "developer" => "Developer".into(),
+            "assistant" => "Assistant".into(),
+            "user" => "User".into(),
+            "tool" => match self.name.as_deref().or(self.tool_call_id.as_deref()) {
+                Some(source) => format!("Tool result ({source})").into(),
+                None => "Tool result".into(),
+            },
+            other => other.into(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -282,33 +1106,196 @@ impl ChatMessageContent {
 #[debug_handler]
 async fn chat_completions(
     State(state): State<SharedState>,
+    Query(query): Query<PrettyQuery>,
     headers: HeaderMap,
     Json(request): Json<ChatCompletionRequest>,
 ) -> Response {
     if let Err(err) = authorize(&state, &headers) {
         return err.into_response();
     }
+    state.stats.record_request();
+
+    if state.strict {
+        if let Err(err) = request.reject_unknown_fields() {
+            return err.into_response();
+        }
+    }
+
+    if let Some(max) = state.max_messages {
+        if let Err(err) = request.check_message_limit(max) {
+            return err.into_response();
+        }
+    }
+
+    let mut request = request;
+    request.upstream_timeout = match requested_timeout(&headers, state.max_request_timeout) {
+        Ok(timeout) => timeout,
+        Err(err) => return err.into_response(),
+    };
+    let truncated = match state.max_message_chars {
+        Some(max_chars) => request.cap_message_lengths(max_chars),
+        None => false,
+    };
+    let dropped_turns = match state.context_budget {
+        Some(budget) => request.fit_context_budget(budget),
+        None => 0,
+    };
+    if dropped_turns > 0 {
+        tracing::info!(
+            dropped_turns,
+            "dropped oldest turns to fit --context-budget"
+        );
+    }
 
-    if request.stream {
+    let started = Instant::now();
+    let mut response = if request.stream && !(state.stream_fallback_json && prefers_json(&headers))
+    {
         chat_completions_stream(state, request).await
     } else {
         match chat_completions_non_stream(&state, request).await {
-            Ok(response) => Json(response).into_response(),
+            Ok((response, meta)) => {
+                let mut response = JsonBody {
+                    value: response,
+                    pretty: wants_pretty(&query, &headers),
+                }
+                .into_response();
+                let headers = response.headers_mut();
+                insert_timing_headers(headers, &meta, started.elapsed());
+                if state.expose_vqd_debug {
+                    insert_vqd_debug_headers(headers, &meta.vqd);
+                }
+                response
+            }
             Err(err) => err.into_response(),
         }
+    };
+    if truncated && response.status().is_success() {
+        response
+            .headers_mut()
+            .insert(TRUNCATED_HEADER, HeaderValue::from_static("true"));
+    }
+    if dropped_turns > 0 && response.status().is_success() {
+        response
+            .headers_mut()
+            .insert(DROPPED_TURNS_HEADER, HeaderValue::from(dropped_turns));
     }
+    response
 }
 
-async fn chat_completions_non_stream(
-    state: &ServerState,
-    request: ChatCompletionRequest,
-) -> ApiResult<ChatCompletionResponse> {
-    if request.messages.is_empty() {
-        return Err(ApiError::bad_request("messages array must not be empty"));
-    }
+/// Client-chosen upstream timeout in seconds, bounded by `--max-request-timeout`.
+const TIMEOUT_HEADER: &str = "x-duckai-timeout";
 
-    let model_id = request
-        .model
+/// Reads the `X-DuckAI-Timeout` override, rejecting malformed values and values above `max`.
+fn requested_timeout(headers: &HeaderMap, max: Duration) -> ApiResult<Option<Duration>> {
+    let Some(value) = headers.get(TIMEOUT_HEADER) else {
+        return Ok(None);
+    };
+    let secs = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .ok_or_else(|| {
+            ApiError::invalid_param(
+                "X-DuckAI-Timeout",
+                "X-DuckAI-Timeout must be a positive whole number of seconds",
+            )
+        })?;
+    if secs > max.as_secs() {
+        return Err(ApiError::invalid_param(
+            "X-DuckAI-Timeout",
+            format!(
+                "X-DuckAI-Timeout of {secs}s exceeds this server's maximum of {}s",
+                max.as_secs()
+            ),
+        ));
+    }
+    Ok(Some(Duration::from_secs(secs)))
+}
+
+/// Set when `--max-message-chars` shortened at least one message.
+const TRUNCATED_HEADER: &str = "x-duckai-truncated";
+/// Number of turns `--context-budget` removed from the conversation.
+const DROPPED_TURNS_HEADER: &str = "x-duckai-dropped-turns";
+
+/// Whether the client asked for JSON rather than an event stream (`--stream-fallback-json`).
+fn prefers_json(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    accept.contains("application/json") && !accept.contains("text/event-stream")
+}
+
+const UPSTREAM_MS_HEADER: &str = "x-duckai-upstream-ms";
+const TOTAL_MS_HEADER: &str = "x-duckai-total-ms";
+
+fn insert_timing_headers(headers: &mut HeaderMap, meta: &CompletionMeta, total: Duration) {
+    headers.insert(UPSTREAM_MS_HEADER, HeaderValue::from(millis(meta.upstream)));
+    headers.insert(TOTAL_MS_HEADER, HeaderValue::from(millis(total)));
+    if let Ok(value) = HeaderValue::from_str(&server_timing(meta, total)) {
+        headers.insert("server-timing", value);
+    }
+}
+
+/// Standard `Server-Timing` value with the same phases, for browser devtools.
+fn server_timing(meta: &CompletionMeta, total: Duration) -> String {
+    format!(
+        "vqd;dur={}, upstream;dur={}, total;dur={}",
+        millis(meta.session_prep),
+        millis(meta.upstream),
+        millis(total)
+    )
+}
+
+const FE_VERSION_HEADER: &str = "x-duckai-fe-version";
+const VQD_HASH_HEADER: &str = "x-duckai-vqd-hash";
+
+fn insert_vqd_debug_headers(headers: &mut HeaderMap, vqd: &VqdSession) {
+    let debug = vqd_debug(vqd);
+    for (name, key) in [
+        (FE_VERSION_HEADER, "fe_version"),
+        (VQD_HASH_HEADER, "vqd_hash"),
+    ] {
+        if let Some(value) = debug[key]
+            .as_str()
+            .and_then(|value| HeaderValue::from_str(value).ok())
+        {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Identifies the session behind a response without revealing the VQD header itself.
+fn vqd_debug(vqd: &VqdSession) -> Value {
+    json!({
+        "fe_version": vqd.fe_version,
+        "vqd_hash": util::sha256_base64(&vqd.vqd_header),
+    })
+}
+
+/// Per-request details reported alongside a non-streaming completion.
+struct CompletionMeta {
+    /// Time spent obtaining the (possibly cached) VQD session.
+    session_prep: Duration,
+    upstream: Duration,
+    vqd: Arc<VqdSession>,
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+async fn chat_completions_non_stream(
+    state: &ServerState,
+    request: ChatCompletionRequest,
+) -> ApiResult<(ChatCompletionResponse, CompletionMeta)> {
+    request.validate()?;
+    let logprobs = request.logprobs_value(state.logprobs_mode)?;
+
+    let model_id = request
+        .model
         .clone()
         .unwrap_or_else(|| state.default_model.clone());
     if !state.allowed_models.contains(model_id.as_str()) {
@@ -317,26 +1304,86 @@ async fn chat_completions_non_stream(
         )));
     }
 
-    let prompt = render_conversation(&request.messages)?;
+    let (prompt, messages) = prepare_conversation(state, &request.messages, &model_id)?;
+    log_prompt(state, &model_id, &prompt);
 
-    let session = HttpSession::new(&state.session_config)
-        .map_err(|err| ApiError::internal(format!("failed to create HTTP session: {err}")))?;
-    let vqd = vqd::prepare_session(&session)
+    let session_started = Instant::now();
+    let (session, vqd) = state
+        .session_and_vqd()
         .await
         .map_err(|err| ApiError::internal(format!("failed to prepare VQD session: {err}")))?;
-    let chat_response = chat::send_chat(&session, &vqd, &prompt, &model_id, None)
-        .await
-        .map_err(|err| ApiError::internal(format!("chat request failed: {err}")))?;
+    let session_prep = session_started.elapsed();
+    let banned = request.banned_patterns();
+    let id = state.completion_ids.next();
+    let upstream_started = Instant::now();
+    let mut formatter = StreamFormatter::new(id.clone(), model_id.clone(), util::unix_now())
+        .with_token_limit(request.completion_token_limit())
+        .with_strip_patterns(state.strip_patterns.clone())
+        .with_banned(banned.clone())
+        .with_trim(state.trim_output)
+        .with_citations(state.include_citations)
+        .with_json_capture(request.wants_json())
+        .with_role(state.response_role.clone())
+        .with_stream_debug(state.stream_debug, state.redact)
+        .collecting();
+    let mut retries_left = usize::from(state.on_empty == OnEmpty::Retry);
+    let (chat_response, content) = loop {
+        // Run the upstream events through the same formatter as streaming clients get.
+        let (raw_tx, raw_rx) = mpsc::channel::<String>(state.stream_buffer);
+        let collector = tokio::spawn(collect_formatted(formatter, raw_rx));
+        let chat_response = chat::ChatRequestBuilder::conversation(messages.clone())
+            .model(model_id.as_str())
+            .temperature(request.temperature)
+            .timeout(request.upstream_timeout)
+            .events(raw_tx)
+            .send(&session, &vqd)
+            .await
+            .map_err(|err| ApiError::internal(format!("chat request failed: {err}")))?;
+        formatter = collector
+            .await
+            .map_err(|err| ApiError::internal(format!("completion collector failed: {err}")))?;
+        state.stats.record_response(&chat_response);
+        record_history(state, &model_id, &prompt, &chat_response);
+
+        if chat_response.status != 200 {
+            state.discard_if_rejected(&vqd, chat_response.status).await;
+            return Err(ApiError::upstream(
+                chat_response.status,
+                chat_response.body,
+                state.error_detail,
+            ));
+        }
 
-    if chat_response.status != 200 {
-        return Err(ApiError::upstream(chat_response.status, chat_response.body));
-    }
+        formatter.check_upstream_error()?;
 
-    let aggregated = extract_completion(&chat_response.body);
-    let created = current_unix_time();
-    let id = format!("chatcmpl-{}", Uuid::new_v4());
+        // Deltas were filtered one at a time; catch banned strings split across them.
+        let content = remove_matches(
+            &banned,
+            &strip_boilerplate(
+                &state.strip_patterns,
+                &collected_completion(&formatter, &chat_response.body, state.trim_output),
+            ),
+        );
+        if !content.is_empty() {
+            break (chat_response, content);
+        }
+        match state.on_empty {
+            OnEmpty::Retry if retries_left > 0 => {
+                retries_left -= 1;
+                tracing::warn!("upstream returned an empty completion; retrying once");
+            }
+            OnEmpty::Retry | OnEmpty::Error => return Err(ApiError::empty_completion()),
+            OnEmpty::Empty => break (chat_response, content),
+        }
+    };
+    let upstream = upstream_started.elapsed();
+
+    formatter.finish_chunk(end_reason(chat_response.completed));
+    let finish_reason = formatter.finish_reason().unwrap_or("stop").to_owned();
+    let model_id = extract_model(&chat_response.body).unwrap_or(model_id);
+    let created = util::unix_now();
 
-    Ok(ChatCompletionResponse {
+    let response = ChatCompletionResponse {
         id,
         object: "chat.completion",
         created,
@@ -344,11 +1391,11 @@ async fn chat_completions_non_stream(
         choices: vec![ChatCompletionChoice {
             index: 0,
             message: AssistantMessage {
-                role: "assistant",
-                content: aggregated,
+                role: state.response_role.to_string(),
+                content: MessageContent::new(content, state.response_content_array),
             },
-            finish_reason: Some("stop".to_owned()),
-            logprobs: None,
+            finish_reason: Some(finish_reason),
+            logprobs: Some(logprobs),
         }],
         usage: Usage {
             prompt_tokens: 0,
@@ -356,13 +1403,30 @@ async fn chat_completions_non_stream(
             total_tokens: 0,
         },
         system_fingerprint: None,
-    })
+        citations: formatter
+            .citations()
+            .filter(|c| !c.is_empty())
+            .map(<[Value]>::to_vec),
+        debug: state
+            .debug_echo_prompt
+            .then(|| json!({ "rendered_prompt": prompt })),
+    };
+    let meta = CompletionMeta {
+        session_prep,
+        upstream,
+        vqd,
+    };
+    Ok((response, meta))
 }
 
 async fn chat_completions_stream(state: ServerState, request: ChatCompletionRequest) -> Response {
-    if request.messages.is_empty() {
-        return ApiError::bad_request("messages array must not be empty").into_response();
+    if let Err(err) = request.validate() {
+        return err.into_response();
     }
+    let logprobs = match request.logprobs_value(state.logprobs_mode) {
+        Ok(value) => value,
+        Err(err) => return err.into_response(),
+    };
 
     let model_id = request
         .model
@@ -373,94 +1437,328 @@ async fn chat_completions_stream(state: ServerState, request: ChatCompletionRequ
             .into_response();
     }
 
-    let prompt = match render_conversation(&request.messages) {
+    let (prompt, messages) = match prepare_conversation(&state, &request.messages, &model_id) {
         Ok(value) => value,
         Err(err) => return err.into_response(),
     };
-
-    let (sender, receiver) = mpsc::channel::<String>(128);
+    log_prompt(&state, &model_id, &prompt);
+
+    let formatter = StreamFormatter::new(
+        state.completion_ids.next(),
+        model_id.clone(),
+        util::unix_now(),
+    )
+    .with_logprobs(logprobs)
+    .with_token_limit(request.completion_token_limit())
+    .with_content_array(state.response_content_array)
+    .with_strip_patterns(state.strip_patterns.clone())
+    .with_banned(request.banned_patterns())
+    .with_trim(state.trim_output)
+    .with_citations(state.include_citations)
+    .with_json_capture(request.wants_json())
+    .with_role(state.response_role.clone())
+    .with_stream_debug(state.stream_debug, state.redact);
+
+    let eager_first_chunk = state.eager_first_chunk;
+    let done_marker = state.done_marker;
+    let error_detail = state.error_detail;
+    let (sender, receiver) = mpsc::channel::<String>(state.stream_buffer);
     let task_sender = sender.clone();
+    let active = state.stats.stream_started();
     tokio::spawn(async move {
-        if let Err(err) = stream_chat_worker(state, prompt, model_id, task_sender.clone()).await {
-            let error_json = json!({
-                "action": "error",
-                "message": err.to_string(),
-            });
-            let _ = task_sender.send(error_json.to_string()).await;
-            let _ = task_sender.send("[DONE]".to_owned()).await;
+        let _active = active;
+        if let Err(err) = stream_chat_worker(
+            state,
+            prompt,
+            messages,
+            model_id,
+            chat::ChatOptions {
+                temperature: request.temperature,
+                timeout: request.upstream_timeout,
+                tools: false,
+            },
+            formatter,
+            task_sender.clone(),
+        )
+        .await
+        {
+            // Never wait on a client that may be the reason for the failure: a stalled one
+            // would hold the task for another full --sse-send-timeout.
+            tracing::warn!("streamed chat completion failed: {err:#}");
+            if task_sender
+                .try_send(stream_error_event(&err, error_detail))
+                .is_ok()
+            {
+                if let Some(done) = done_marker.payload() {
+                    let _ = task_sender.try_send(done);
+                }
+            }
         }
     });
     drop(sender);
 
     let stream = ReceiverStream::new(receiver)
         .map(|payload| Ok::<Event, Infallible>(Event::default().data(payload)));
-    Sse::new(stream).into_response()
+    let sse = Sse::new(stream);
+    if eager_first_chunk {
+        sse.keep_alive(KeepAlive::new().interval(KEEP_ALIVE_INTERVAL))
+            .into_response()
+    } else {
+        sse.into_response()
+    }
+}
+
+/// OpenAI-style `{"error": {...}}` event reporting a failure after the stream has started.
+/// Upstream errors keep Duck.ai's `type` as the code; other causes are only spelled out
+/// under `--error-detail full`.
+fn stream_error_event(err: &anyhow::Error, detail: ErrorDetail) -> String {
+    let upstream = err.downcast_ref::<UpstreamErrorEvent>();
+    let message = match (upstream, detail) {
+        (Some(event), _) => event.message.clone(),
+        (None, ErrorDetail::Full) => format!("{err:#}"),
+        (None, ErrorDetail::Minimal) => "chat completion failed".to_owned(),
+    };
+    error_event(message, upstream.and_then(|event| event.kind.clone()))
 }
 
+fn error_event(message: String, code: Option<String>) -> String {
+    let body = ApiErrorBody {
+        error: ApiErrorDetail {
+            message,
+            error_type: "upstream_error".to_owned(),
+            param: None,
+            code,
+        },
+    };
+    serde_json::to_string(&body).unwrap_or_default()
+}
+
+/// Interval of SSE comment keep-alives sent with `--eager-first-chunk`.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
 async fn stream_chat_worker(
     state: ServerState,
     prompt: String,
+    messages: Vec<ChatMessage>,
     model_id: String,
+    upstream: chat::ChatOptions,
+    formatter: StreamFormatter,
     sender: mpsc::Sender<String>,
 ) -> crate::error::Result<()> {
-    let (raw_tx, mut raw_rx) = mpsc::channel::<String>(128);
-    let stream_id = format!("chatcmpl-{}", Uuid::new_v4());
-    let start_created = current_unix_time();
-    let formatter_sender = sender.clone();
-    let formatter = StreamFormatter::new(stream_id, model_id.clone(), start_created);
+    let mut formatter = formatter;
+    if state.eager_first_chunk {
+        for chunk in formatter.eager_chunks() {
+            send_to_client(&sender, chunk, state.sse_send_timeout).await;
+        }
+    }
 
-    tokio::spawn(async move {
-        let sender = formatter_sender;
-        let mut formatter = formatter;
-        while let Some(payload) = raw_rx.recv().await {
-            if payload == "[DONE]" {
-                if let Some(final_chunk) = formatter.finish_chunk("stop") {
-                    let _ = sender.send(final_chunk).await;
-                }
-                let _ = sender.send("[DONE]".to_owned()).await;
-                return;
+    let (session, vqd) = state
+        .session_and_vqd()
+        .await
+        .context("failed to prepare VQD session")?;
+
+    if state.expose_vqd_debug {
+        formatter.vqd_debug = Some(vqd_debug(&vqd));
+    }
+    formatter.start_upstream_timer();
+    let mut retries_left = usize::from(state.on_empty == OnEmpty::Retry);
+
+    let completed = loop {
+        let (raw_tx, raw_rx) = mpsc::channel::<String>(state.stream_buffer);
+        let forwarder = tokio::spawn(forward_formatted(
+            formatter,
+            raw_rx,
+            sender.clone(),
+            state.sse_send_timeout,
+        ));
+
+        let chat_response =
+            chat::send_chat(&session, &vqd, &messages, &model_id, upstream, Some(raw_tx))
+                .await
+                .context("chat request failed")?;
+        let delivery;
+        (formatter, delivery) = forwarder.await.context("stream formatter task failed")?;
+        state.stats.record_response(&chat_response);
+        record_history(&state, &model_id, &prompt, &chat_response);
+        if delivery == Delivery::Stalled {
+            return Err(anyhow!(
+                "client stopped reading the stream; aborting after --sse-send-timeout"
+            ));
+        }
+
+        if chat_response.status != 200 {
+            state.discard_if_rejected(&vqd, chat_response.status).await;
+            return Err(UpstreamErrorEvent {
+                message: upstream_error_message(
+                    chat_response.status,
+                    &chat_response.body,
+                    state.error_detail,
+                ),
+                kind: upstream_error_type(&chat_response.body),
             }
+            .into());
+        }
 
-            match formatter.process_payload(&payload) {
-                Ok(chunks) => {
-                    for chunk in chunks {
-                        if sender.send(chunk).await.is_err() {
-                            return;
-                        }
-                    }
-                }
-                Err(err) => {
-                    tracing::warn!("Failed to process upstream chunk: {err}");
-                }
+        // The error event already went out in place of a final chunk.
+        if formatter.has_content() || formatter.upstream_error.is_some() {
+            break chat_response.completed;
+        }
+        match state.on_empty {
+            OnEmpty::Retry if retries_left > 0 => {
+                retries_left -= 1;
+                tracing::warn!("upstream returned an empty completion; retrying once");
+            }
+            OnEmpty::Retry | OnEmpty::Error => {
+                return Err(anyhow!("upstream returned an empty completion"));
             }
+            OnEmpty::Empty => break chat_response.completed,
         }
+    };
+
+    if let Some(final_chunk) = formatter.finish_chunk(end_reason(completed)) {
+        send_to_client(&sender, final_chunk, state.sse_send_timeout).await;
+    }
+    if let Some(done) = state.done_marker.payload() {
+        send_to_client(&sender, done, state.sse_send_timeout).await;
+    }
+
+    Ok(())
+}
+
+/// Reports a severed upstream stream as `length` rather than a clean `stop`.
+fn end_reason(completed: bool) -> &'static str {
+    if completed {
+        "stop"
+    } else {
+        tracing::warn!("upstream stream closed without a terminal event");
+        "length"
+    }
+}
 
-        if let Some(final_chunk) = formatter.finish_chunk("stop") {
-            let _ = sender.send(final_chunk).await;
+/// Feeds upstream payloads through a collecting formatter for the non-streaming path.
+async fn collect_formatted(
+    mut formatter: StreamFormatter,
+    mut raw_rx: mpsc::Receiver<String>,
+) -> StreamFormatter {
+    while let Some(payload) = raw_rx.recv().await {
+        if payload == "[DONE]" {
+            continue;
         }
-        let _ = sender.send("[DONE]".to_owned()).await;
-    });
+        if let Err(err) = formatter.process_payload(&payload) {
+            tracing::warn!("Failed to process upstream chunk: {err}");
+        }
+    }
+    formatter.flush_buffered();
+    formatter
+}
 
-    let session =
-        HttpSession::new(&state.session_config).context("failed to create HTTP session")?;
-    let vqd = vqd::prepare_session(&session)
-        .await
-        .context("failed to prepare VQD session")?;
+/// Reshapes upstream payloads into OpenAI chunks until the upstream channel closes, then
+/// hands the formatter back so the caller can decide how the stream ends.
+async fn forward_formatted(
+    mut formatter: StreamFormatter,
+    mut raw_rx: mpsc::Receiver<String>,
+    sender: mpsc::Sender<String>,
+    send_timeout: Option<Duration>,
+) -> (StreamFormatter, Delivery) {
+    while let Some(payload) = raw_rx.recv().await {
+        if payload == "[DONE]" {
+            continue;
+        }
 
-    let chat_response = chat::send_chat(&session, &vqd, &prompt, &model_id, Some(raw_tx))
-        .await
-        .context("chat request failed")?;
+        match formatter.process_payload(&payload) {
+            Ok(chunks) => {
+                for chunk in chunks {
+                    let delivery = send_to_client(&sender, chunk, send_timeout).await;
+                    if delivery != Delivery::Sent {
+                        return (formatter, delivery);
+                    }
+                }
+            }
+            Err(err) => {
+                tracing::warn!("Failed to process upstream chunk: {err}");
+            }
+        }
+    }
+    for chunk in formatter.flush_buffered() {
+        let delivery = send_to_client(&sender, chunk, send_timeout).await;
+        if delivery != Delivery::Sent {
+            return (formatter, delivery);
+        }
+    }
+    (formatter, Delivery::Sent)
+}
 
-    if chat_response.status != 200 {
-        let truncated = chat_response.body.chars().take(5000).collect::<String>();
-        return Err(anyhow!(
-            "Upstream duck.ai error (status {}): {}",
-            chat_response.status,
-            truncated
-        ));
+/// Outcome of handing a chunk to the SSE client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    Sent,
+    /// The client disconnected.
+    Closed,
+    /// The client stopped reading for longer than `--sse-send-timeout`.
+    Stalled,
+}
+
+/// Queues a chunk for the SSE client. A full channel normally backpressures the upstream
+/// read; with `--sse-send-timeout` a client that stays stuck is given up on instead.
+async fn send_to_client(
+    sender: &mpsc::Sender<String>,
+    chunk: String,
+    timeout: Option<Duration>,
+) -> Delivery {
+    let send = sender.send(chunk);
+    let result = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, send).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!("SSE client did not read within {timeout:?}; aborting stream");
+                return Delivery::Stalled;
+            }
+        },
+        None => send.await,
+    };
+    match result {
+        Ok(()) => Delivery::Sent,
+        Err(_) => Delivery::Closed,
     }
+}
 
-    Ok(())
+/// Renders the conversation for logging and history, and builds the messages sent upstream.
+/// A conversation shortened by `--truncate-prompt` is sent as one user message.
+fn prepare_conversation(
+    state: &ServerState,
+    messages: &[IncomingMessage],
+    model_id: &str,
+) -> ApiResult<(String, Vec<ChatMessage>)> {
+    let rendered = render_conversation(messages)?;
+    let prompt = model::fit_prompt(rendered.clone(), model_id, state.truncate_prompt)
+        .map_err(|message| ApiError::invalid_param("messages", message))?;
+    let upstream = if prompt == rendered {
+        conversation_messages(messages)
+    } else {
+        vec![ChatMessage::user(prompt.as_str())]
+    };
+    Ok((prompt, upstream))
+}
+
+/// Maps OpenAI messages onto DuckAI roles. Roles DuckAI lacks keep their label in the text.
+fn conversation_messages(messages: &[IncomingMessage]) -> Vec<ChatMessage> {
+    messages
+        .iter()
+        .filter_map(|message| {
+            let text = message.text();
+            if text.is_empty() {
+                return None;
+            }
+            let content = match message.role.as_str() {
+                "user" | "assistant" => text,
+                _ => format!("{}: {text}", message.label()),
+            };
+            Some(ChatMessage::new(
+                ChatRole::from_openai(&message.role),
+                content,
+            ))
+        })
+        .collect()
 }
 
 fn render_conversation(messages: &[IncomingMessage]) -> ApiResult<String> {
@@ -468,20 +1766,12 @@ fn render_conversation(messages: &[IncomingMessage]) -> ApiResult<String> {
     let mut has_user = false;
 
     for message in messages {
-        let text = message.content.render();
+        let text = message.text();
         if text.is_empty() {
             continue;
         }
-        let label = match message.role.as_str() {
-            "system" => "System",
-            "assistant" => "Assistant",
-            "user" => {
-                has_user = true;
-                "User"
-            }
-            other => other,
-        };
-        sections.push(format!("{label}: {text}"));
+        has_user |= message.role == "user";
+        sections.push(format!("{}: {text}", message.label()));
     }
 
     if !has_user {
@@ -497,71 +1787,69 @@ fn render_conversation(messages: &[IncomingMessage]) -> ApiResult<String> {
     Ok(sections.join("\n\n"))
 }
 
-fn extract_completion(body: &str) -> String {
-    let mut assembled = String::new();
-
-    for line in body.lines() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            continue;
-        }
-        let data = trimmed
-            .strip_prefix("data:")
-            .map(str::trim)
-            .unwrap_or(trimmed);
-        if data == "[DONE]" {
-            break;
-        }
-
-        if let Ok(json) = serde_json::from_str::<Value>(data) {
-            if let Some(text) = json.get("message").and_then(Value::as_str) {
-                append_segment(&mut assembled, text);
-                continue;
-            }
-            if let Some(text) = json.get("content").and_then(|v| {
-                if v.is_array() {
-                    v.as_array().map(|items| {
-                        items
-                            .iter()
-                            .filter_map(|item| item.get("text").and_then(Value::as_str))
-                            .collect::<Vec<_>>()
-                            .join("")
-                    })
-                } else {
-                    v.as_str().map(|s| s.to_owned())
-                }
-            }) {
-                if !text.is_empty() {
-                    append_segment(&mut assembled, text.trim());
-                }
-                continue;
-            }
-            if let Some(text) = json.get("body").and_then(Value::as_str) {
-                append_segment(&mut assembled, text);
-                continue;
-            }
-        }
+/// Removes every match of `patterns` from `text`.
+fn remove_matches(patterns: &[Regex], text: &str) -> String {
+    patterns.iter().fold(text.to_owned(), |text, pattern| {
+        pattern.replace_all(&text, "").into_owned()
+    })
+}
 
-        append_segment(&mut assembled, data);
+/// Removes every `--strip-pattern` match from a complete response.
+fn strip_boilerplate(patterns: &[Regex], text: &str) -> String {
+    let stripped = remove_matches(patterns, text);
+    if stripped.len() == text.len() {
+        stripped
+    } else {
+        stripped.trim_start().to_owned()
     }
+}
 
-    let trimmed = assembled.trim();
-    if trimmed.is_empty() {
-        body.trim().to_owned()
+/// Text collected by a non-streaming formatter, falling back to the raw body when upstream
+/// answered with plain text instead of SSE.
+fn collected_completion(formatter: &StreamFormatter, body: &str, trim: TrimOutput) -> String {
+    let collected = formatter.collected_text();
+    if collected.is_empty() && !chat::is_sse_body(body) {
+        trim.apply(body).to_owned()
     } else {
-        trimmed.to_owned()
+        collected.to_owned()
     }
 }
 
-fn append_segment(buffer: &mut String, segment: &str) {
-    let segment = segment.trim();
-    if segment.is_empty() {
+/// Stores the exchange in the `--db` history without blocking the request.
+fn record_history(state: &ServerState, model_id: &str, prompt: &str, response: &ChatResponse) {
+    let Some(history) = state.history.clone() else {
         return;
-    }
-    if !buffer.is_empty() {
-        buffer.push('\n');
-    }
-    buffer.push_str(segment);
+    };
+    let entry = HistoryEntry::new(model_id, prompt, response, state.redact);
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = history.record(&entry) {
+            tracing::warn!("failed to record history: {err:?}");
+        }
+    });
+}
+
+fn log_prompt(state: &ServerState, model_id: &str, prompt: &str) {
+    tracing::debug!(
+        model = %model_id,
+        prompt = %util::loggable(prompt, state.redact),
+        "rendered conversation prompt"
+    );
+}
+
+/// Returns the last non-empty `model` reported by the upstream SSE payloads, mirroring how
+/// `StreamFormatter` tracks the model during streaming.
+fn extract_model(body: &str) -> Option<String> {
+    body.lines()
+        .rev()
+        .filter_map(|line| line.trim().strip_prefix("data:"))
+        .filter_map(|data| serde_json::from_str::<Value>(data.trim()).ok())
+        .filter_map(|json| {
+            json.get("model")
+                .and_then(Value::as_str)
+                .filter(|model| !model.is_empty())
+                .map(str::to_owned)
+        })
+        .next()
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -574,6 +1862,12 @@ struct ChatCompletionResponse {
     usage: Usage,
     #[serde(skip_serializing_if = "Option::is_none")]
     system_fingerprint: Option<String>,
+    /// Sources reported by Duck.ai (`--include-citations`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    citations: Option<Vec<Value>>,
+    /// Server-side details for client authors (`--debug-echo-prompt`).
+    #[serde(rename = "_debug", skip_serializing_if = "Option::is_none")]
+    debug: Option<Value>,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -582,14 +1876,38 @@ struct ChatCompletionChoice {
     message: AssistantMessage,
     #[serde(skip_serializing_if = "Option::is_none")]
     finish_reason: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
     logprobs: Option<Value>,
 }
 
 #[derive(Clone, Debug, Serialize)]
 struct AssistantMessage {
-    role: &'static str,
-    content: String,
+    role: String,
+    content: MessageContent,
+}
+
+/// Assistant content: a plain string, or a list of text parts with `--response-content-array`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<TextPart>),
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct TextPart {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    text: String,
+}
+
+impl MessageContent {
+    fn new(text: String, as_array: bool) -> Self {
+        if as_array {
+            Self::Parts(vec![TextPart { kind: "text", text }])
+        } else {
+            Self::Text(text)
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -599,11 +1917,40 @@ struct Usage {
     total_tokens: u32,
 }
 
+/// Longest opening `--strip-pattern` waits for before giving up on a line break.
+const STRIP_HEAD_CHARS: usize = 512;
+
 struct StreamFormatter {
     id: String,
     model: String,
     created: u64,
+    logprobs: Value,
+    started: Instant,
+    upstream_started: Option<Instant>,
+    vqd_debug: Option<Value>,
+    remaining_chars: Option<usize>,
+    content_array: bool,
+    strip_patterns: Arc<[Regex]>,
+    /// Strings banned through `logit_bias`, removed from every delta.
+    banned: Vec<Regex>,
+    /// Opening text held back until `--strip-pattern` can be applied to a whole line.
+    head: Option<String>,
+    trim: TrimOutput,
+    /// Trailing whitespace held back until more text follows, dropped if the reply ends.
+    pending_whitespace: String,
+    /// `Some` with `--include-citations`: sources seen so far, as `{url, title}` objects.
+    citations: Option<Vec<Value>>,
+    /// Set for JSON-mode requests: output is held back until a whole JSON value is formed.
+    json: Option<JsonCapture>,
+    role: Arc<str>,
+    /// Error event sent by Duck.ai inside an otherwise successful response.
+    upstream_error: Option<UpstreamErrorEvent>,
+    collected: Option<String>,
+    finish_reason: Option<String>,
+    /// `Some(redact)` when `--stream-debug` logging is on.
+    stream_debug: Option<bool>,
     sent_role: bool,
+    sent_content: bool,
     finished: bool,
 }
 
@@ -613,14 +1960,230 @@ impl StreamFormatter {
             id,
             model,
             created,
+            logprobs: Value::Null,
+            started: Instant::now(),
+            upstream_started: None,
+            vqd_debug: None,
+            remaining_chars: None,
+            content_array: false,
+            strip_patterns: Arc::from([]),
+            banned: Vec::new(),
+            head: None,
+            trim: TrimOutput::None,
+            pending_whitespace: String::new(),
+            citations: None,
+            json: None,
+            role: Arc::from(DEFAULT_RESPONSE_ROLE),
+            upstream_error: None,
+            collected: None,
+            finish_reason: None,
+            stream_debug: None,
             sent_role: false,
+            sent_content: false,
             finished: false,
         }
     }
 
+    fn with_logprobs(mut self, logprobs: Value) -> Self {
+        self.logprobs = logprobs;
+        self
+    }
+
+    fn with_token_limit(mut self, limit: Option<usize>) -> Self {
+        self.remaining_chars = limit.map(|limit| limit * util::CHARS_PER_TOKEN);
+        self
+    }
+
+    fn with_content_array(mut self, content_array: bool) -> Self {
+        self.content_array = content_array;
+        self
+    }
+
+    fn with_strip_patterns(mut self, patterns: Arc<[Regex]>) -> Self {
+        self.head = (!patterns.is_empty()).then(String::new);
+        self.strip_patterns = patterns;
+        self
+    }
+
+    fn with_banned(mut self, banned: Vec<Regex>) -> Self {
+        self.banned = banned;
+        self
+    }
+
+    fn with_trim(mut self, trim: TrimOutput) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    fn with_citations(mut self, enabled: bool) -> Self {
+        self.citations = enabled.then(Vec::new);
+        self
+    }
+
+    fn with_role(mut self, role: Arc<str>) -> Self {
+        self.role = role;
+        self
+    }
+
+    fn with_json_capture(mut self, enabled: bool) -> Self {
+        self.json = enabled.then(JsonCapture::default);
+        self
+    }
+
+    fn citations(&self) -> Option<&[Value]> {
+        self.citations.as_deref()
+    }
+
+    /// Records citations carried by an upstream payload, returning those not seen before.
+    fn new_citations(&mut self, payload: &Value) -> Vec<Value> {
+        let Some(seen) = self.citations.as_mut() else {
+            return Vec::new();
+        };
+        let mut fresh = Vec::new();
+        for citation in payload_citations(payload) {
+            if !seen.iter().any(|known| known["url"] == citation["url"]) {
+                seen.push(citation.clone());
+                fresh.push(citation);
+            }
+        }
+        fresh
+    }
+
+    /// Applies `--trim-output` to a delta: leading whitespace is dropped before the first
+    /// content, and trailing whitespace waits for the next delta so the reply never ends on it.
+    fn trim_delta(&mut self, message: String) -> String {
+        if self.trim == TrimOutput::None || message.is_empty() {
+            return message;
+        }
+        let message = if self.trim == TrimOutput::Both && !self.sent_content {
+            message.trim_start()
+        } else {
+            message.as_str()
+        };
+        let body = message.trim_end();
+        if body.is_empty() {
+            if self.trim == TrimOutput::Trailing || self.sent_content {
+                self.pending_whitespace.push_str(message);
+            }
+            return String::new();
+        }
+        let mut delta = std::mem::take(&mut self.pending_whitespace);
+        delta.push_str(body);
+        self.pending_whitespace.push_str(&message[body.len()..]);
+        delta
+    }
+
+    /// Also accumulates emitted content so a non-streaming response can be built from it.
+    fn collecting(mut self) -> Self {
+        self.collected = Some(String::new());
+        self
+    }
+
+    fn collected_text(&self) -> &str {
+        self.collected.as_deref().unwrap_or_default()
+    }
+
+    /// Fails when Duck.ai answered HTTP 200 but streamed an `error` action.
+    fn check_upstream_error(&self) -> ApiResult<()> {
+        match &self.upstream_error {
+            Some(event) => Err(ApiError::upstream_event(event)),
+            None => Ok(()),
+        }
+    }
+
+    fn finish_reason(&self) -> Option<&str> {
+        self.finish_reason.as_deref()
+    }
+
+    /// Buffers the opening of the reply until a full line (or [`STRIP_HEAD_CHARS`]) has
+    /// arrived, then strips it. Later deltas pass through untouched.
+    fn strip_head(&mut self, message: &str) -> String {
+        let Some(head) = self.head.as_mut() else {
+            return message.to_owned();
+        };
+        head.push_str(message);
+        if !head.contains('\n') && head.chars().count() < STRIP_HEAD_CHARS {
+            return String::new();
+        }
+        self.take_head()
+    }
+
+    /// Strips the buffered opening. Keeps buffering when nothing but boilerplate was seen.
+    fn take_head(&mut self) -> String {
+        let head = self.head.take().unwrap_or_default();
+        let stripped = strip_boilerplate(&self.strip_patterns, &head);
+        if stripped.trim().is_empty() {
+            self.head = Some(String::new());
+            return String::new();
+        }
+        stripped
+    }
+
+    /// Emits whatever opening text or partial JSON value is still buffered once upstream
+    /// has finished, through the same trimming and token limit as streamed deltas.
+    fn flush_buffered(&mut self) -> Vec<String> {
+        let mut chunks = Vec::new();
+        if self.finished {
+            return chunks;
+        }
+        let text = match self.head {
+            Some(_) => self.take_head(),
+            None => String::new(),
+        };
+        let mut text = self.trim_delta(text);
+        if let Some(capture) = self.json.as_mut() {
+            let mut value = capture.push(&text).unwrap_or_default();
+            value.extend(capture.finish());
+            text = value;
+        }
+        self.push_limited(&text, &mut chunks);
+        chunks
+    }
+
+    /// Emits a content delta cut to the remaining `max_tokens` budget, ending the stream
+    /// with `finish_reason: "length"` once the budget runs out.
+    fn push_limited(&mut self, message: &str, chunks: &mut Vec<String>) {
+        let (message, truncated) = match self.remaining_chars {
+            Some(remaining) => util::truncate_chars(message, remaining),
+            None => (message, false),
+        };
+        if let Some(remaining) = self.remaining_chars.as_mut() {
+            *remaining -= message.chars().count();
+        }
+        if !message.is_empty() {
+            chunks.push(self.build_content_chunk(message));
+        }
+        if truncated {
+            chunks.extend(self.finish_chunk("length"));
+        }
+    }
+
+    /// Marks the start of the upstream chat request for the trailing timing report.
+    fn start_upstream_timer(&mut self) {
+        self.upstream_started = Some(Instant::now());
+    }
+
+    /// Logs every upstream payload with the chunks it became (`--stream-debug`).
+    fn with_stream_debug(mut self, enabled: bool, redact: bool) -> Self {
+        self.stream_debug = enabled.then_some(redact);
+        self
+    }
+
     fn process_payload(&mut self, payload: &str) -> crate::error::Result<Vec<String>> {
+        let chunks = self.reshape_payload(payload)?;
+        if let Some(redact) = self.stream_debug {
+            tracing::debug!(
+                raw = %util::loggable(payload.trim(), redact),
+                chunks = %util::loggable(&chunks.join("\n"), redact),
+                "reshaped upstream payload"
+            );
+        }
+        Ok(chunks)
+    }
+
+    fn reshape_payload(&mut self, payload: &str) -> crate::error::Result<Vec<String>> {
         let trimmed = payload.trim();
-        if trimmed.is_empty() {
+        if trimmed.is_empty() || self.finished {
             return Ok(Vec::new());
         }
 
@@ -637,32 +2200,54 @@ impl StreamFormatter {
         }
 
         let action = value.get("action").and_then(|v| v.as_str()).unwrap_or("");
-        let role = value
-            .get("role")
-            .and_then(|v| v.as_str())
-            .unwrap_or("assistant");
         let message = value.get("message").and_then(|v| v.as_str()).unwrap_or("");
 
         let mut chunks = Vec::new();
+        let citations = self.new_citations(&value);
+        if !citations.is_empty() {
+            if !self.sent_role {
+                chunks.push(self.build_role_chunk());
+                self.sent_role = true;
+            }
+            chunks.push(self.build_chunk(json!({ "citations": citations }), None, false));
+        }
 
         if action == "success" {
             if !self.sent_role {
-                chunks.push(self.build_role_chunk(role));
+                chunks.push(self.build_role_chunk());
                 self.sent_role = true;
             }
-            if !message.is_empty() {
-                chunks.push(self.build_content_chunk(message));
-            }
+            let message = self.strip_head(&remove_matches(&self.banned, message));
+            let message = self.trim_delta(message);
+            let message = match self.json.as_mut() {
+                Some(capture) => capture.push(&message).unwrap_or_default(),
+                None => message,
+            };
+            self.push_limited(&message, &mut chunks);
+        } else if action == "challenge_pending" {
+            // Not an OpenAI chunk: tells the client that manual verification is pending.
+            let url = value.get("url").cloned().unwrap_or(Value::Null);
+            chunks.push(json!({ "type": "challenge_pending", "url": url }).to_string());
         } else if action == "error" {
-            let error_message = if message.is_empty() {
-                "upstream error"
-            } else {
-                message
+            let kind = value.get("type").and_then(Value::as_str);
+            let error_message = match (message, kind) {
+                ("", Some(kind)) => kind,
+                ("", None) => "upstream error",
+                (message, _) => message,
             };
-            chunks.push(self.build_content_chunk(error_message));
-            if let Some(final_chunk) = self.finish_chunk("error") {
-                chunks.push(final_chunk);
+            let event = UpstreamErrorEvent {
+                message: error_message.to_owned(),
+                kind: kind.map(str::to_owned),
+            };
+            chunks.extend(self.flush_buffered());
+            if self.finished {
+                return Ok(chunks);
             }
+            // Sent as an OpenAI error event, never as assistant content.
+            let error = ApiError::upstream_event(&event).body.error;
+            chunks.push(error_event(error.message, error.code));
+            self.upstream_error = Some(event);
+            self.finished = true;
         }
 
         Ok(chunks)
@@ -673,14 +2258,37 @@ impl StreamFormatter {
             return None;
         }
         self.finished = true;
+        self.finish_reason = Some(reason.to_owned());
         Some(self.build_chunk(json!({}), Some(reason), true))
     }
 
-    fn build_role_chunk(&self, role: &str) -> String {
-        self.build_chunk(json!({ "role": role }), None, false)
+    /// Opening delta naming the response role. Upstream's own `role` field is ignored so
+    /// streamed and non-streamed responses always agree.
+    fn build_role_chunk(&self) -> String {
+        self.build_chunk(json!({ "role": self.role.as_ref() }), None, false)
+    }
+
+    /// Role chunk plus an empty content delta, sent before upstream output so clients show
+    /// activity during slow first tokens. Does not count as content for on-empty handling.
+    fn eager_chunks(&mut self) -> Vec<String> {
+        self.sent_role = true;
+        let empty = MessageContent::new(String::new(), self.content_array);
+        vec![
+            self.build_role_chunk(),
+            self.build_chunk(json!({ "content": empty }), None, false),
+        ]
+    }
+
+    fn has_content(&self) -> bool {
+        self.sent_content
     }
 
-    fn build_content_chunk(&self, content: &str) -> String {
+    fn build_content_chunk(&mut self, content: &str) -> String {
+        self.sent_content = true;
+        if let Some(collected) = self.collected.as_mut() {
+            collected.push_str(content);
+        }
+        let content = MessageContent::new(content.to_owned(), self.content_array);
         self.build_chunk(json!({ "content": content }), None, false)
     }
 
@@ -700,7 +2308,7 @@ impl StreamFormatter {
                     "index": 0,
                     "delta": delta,
                     "finish_reason": finish_reason.map(Value::from).unwrap_or(Value::Null),
-                    "logprobs": Value::Null
+                    "logprobs": self.logprobs.clone()
                 }
             ],
         });
@@ -711,33 +2319,999 @@ impl StreamFormatter {
                 "completion_tokens": 0,
                 "total_tokens": 0,
             });
+            // Streaming responses cannot carry timing headers, so report them on the last chunk.
+            chunk["timings"] = json!({
+                "upstream_ms": self.upstream_started.map(|at| millis(at.elapsed())),
+                "total_ms": millis(self.started.elapsed()),
+            });
+            if let Some(vqd) = &self.vqd_debug {
+                chunk["vqd"] = vqd.clone();
+            }
         }
 
         chunk.to_string()
     }
 }
 
+/// Source links in an upstream payload's `citations` or `sources` array, normalised to
+/// `{"url": ..., "title": ...}`. Entries may be bare URLs or objects with `url`/`link`/`href`.
+fn payload_citations(payload: &Value) -> Vec<Value> {
+    let Some(entries) = ["citations", "sources"]
+        .iter()
+        .find_map(|key| payload.get(key).and_then(Value::as_array))
+    else {
+        return Vec::new();
+    };
+    entries
+        .iter()
+        .filter_map(|entry| {
+            if let Some(url) = entry.as_str() {
+                return Some(json!({ "url": url, "title": Value::Null }));
+            }
+            let url = ["url", "link", "href"]
+                .iter()
+                .find_map(|key| entry.get(key).and_then(Value::as_str))?;
+            let title = ["title", "name"]
+                .iter()
+                .find_map(|key| entry.get(key).and_then(Value::as_str));
+            Some(json!({ "url": url, "title": title }))
+        })
+        .collect()
+}
+
 fn authorize(state: &ServerState, headers: &HeaderMap) -> ApiResult<()> {
-    if let Some(expected) = &state.auth_header {
-        let provided = headers
-            .get(AUTHORIZATION)
-            .and_then(|value| value.to_str().ok())
-            .map(str::trim);
-        match provided {
-            Some(value) if value == expected => Ok(()),
-            Some(_) => Err(ApiError::unauthorized("invalid API key provided")),
-            None => Err(ApiError::unauthorized(
-                "missing Authorization header with Bearer token",
-            )),
-        }
-    } else {
-        Ok(())
+    match &state.auth_header {
+        Some(expected) => check_bearer(headers, expected, ""),
+        None => Ok(()),
     }
 }
 
-fn current_unix_time() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|duration| duration.as_secs())
-        .unwrap_or(0)
+/// Admin endpoints use the dedicated admin key when configured, else the regular API key.
+/// With neither configured they are refused, since a rotation forces a full upstream handshake.
+fn authorize_admin(state: &ServerState, headers: &HeaderMap) -> ApiResult<()> {
+    match (&state.admin_auth_header, &state.auth_header) {
+        (Some(expected), _) => check_bearer(headers, expected, "admin "),
+        (None, Some(expected)) => check_bearer(headers, expected, ""),
+        (None, None) => Err(ApiError::forbidden(
+            "admin endpoints are disabled: set --server-api-key or --admin-api-key",
+        )),
+    }
+}
+
+/// Compares the `Authorization` header with the expected `Bearer …` value; `scope` prefixes
+/// the key name in error messages.
+fn check_bearer(headers: &HeaderMap, expected: &str, scope: &str) -> ApiResult<()> {
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim);
+    match provided {
+        Some(value) if value == expected => Ok(()),
+        Some(_) => Err(ApiError::unauthorized(format!(
+            "invalid {scope}API key provided"
+        ))),
+        None => Err(ApiError::unauthorized(format!(
+            "missing Authorization header with {scope}Bearer token"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_from(value: Value) -> ChatCompletionRequest {
+        serde_json::from_value(value).expect("valid request")
+    }
+
+    fn rejected_param(value: Value) -> Option<String> {
+        request_from(value)
+            .validate()
+            .err()
+            .and_then(|err| err.body.error.param)
+    }
+
+    #[test]
+    fn accepts_params_within_range() {
+        let request = request_from(json!({
+            "messages": [{ "role": "user", "content": "hi" }],
+            "temperature": 2.0,
+            "top_p": 0.0,
+            "n": 1,
+            "max_tokens": 16,
+        }));
+        assert!(request.validate().is_ok());
+    }
+
+    #[test]
+    fn reports_offending_param() {
+        let messages = json!([{ "role": "user", "content": "hi" }]);
+        assert_eq!(
+            rejected_param(json!({ "messages": messages, "temperature": 2.5 })).as_deref(),
+            Some("temperature")
+        );
+        assert_eq!(
+            rejected_param(json!({ "messages": messages, "top_p": -0.1 })).as_deref(),
+            Some("top_p")
+        );
+        assert_eq!(
+            rejected_param(json!({ "messages": messages, "n": 0 })).as_deref(),
+            Some("n")
+        );
+        assert_eq!(
+            rejected_param(json!({ "messages": messages, "max_tokens": 0 })).as_deref(),
+            Some("max_tokens")
+        );
+        assert_eq!(
+            rejected_param(json!({ "messages": [] })).as_deref(),
+            Some("messages")
+        );
+    }
+
+    #[test]
+    fn extracts_upstream_model_from_body() {
+        let body = concat!(
+            "data: {\"action\":\"success\",\"message\":\"Hi\",\"model\":\"gpt-5-mini-2025\"}\n\n",
+            "data: {\"action\":\"success\",\"message\":\"!\",\"model\":\"\"}\n\n",
+            "data: [DONE]\n\n",
+        );
+        assert_eq!(extract_model(body).as_deref(), Some("gpt-5-mini-2025"));
+        assert_eq!(extract_model("data: {\"message\":\"Hi\"}"), None);
+    }
+
+    #[test]
+    fn content_array_mode_wraps_text_parts() {
+        assert_eq!(
+            serde_json::to_value(MessageContent::new("hi".to_owned(), false)).unwrap(),
+            json!("hi")
+        );
+        assert_eq!(
+            serde_json::to_value(MessageContent::new("hi".to_owned(), true)).unwrap(),
+            json!([{ "type": "text", "text": "hi" }])
+        );
+
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0)
+            .with_content_array(true);
+        let chunk: Value = serde_json::from_str(&formatter.build_content_chunk("hi")).unwrap();
+        assert_eq!(
+            chunk["choices"][0]["delta"]["content"],
+            json!([{ "type": "text", "text": "hi" }])
+        );
+    }
+
+    #[test]
+    fn done_marker_variants() {
+        assert_eq!(DoneMarker::Standard.payload().as_deref(), Some("[DONE]"));
+        assert_eq!(DoneMarker::Omit.payload(), None);
+        assert_eq!(
+            DoneMarker::Json.payload().as_deref(),
+            Some(r#"{"done":true}"#)
+        );
+    }
+
+    #[test]
+    fn trim_output_applies_across_deltas() {
+        let collect = |trim: TrimOutput| {
+            let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0)
+                .with_trim(trim)
+                .collecting();
+            for message in ["\n  ", "  fn main() {}", " \n", "\n", "// end\n\n"] {
+                let payload = json!({ "action": "success", "message": message }).to_string();
+                formatter.process_payload(&payload).unwrap();
+            }
+            formatter.collected_text().to_owned()
+        };
+        assert_eq!(
+            collect(TrimOutput::None),
+            "\n    fn main() {} \n\n// end\n\n"
+        );
+        assert_eq!(
+            collect(TrimOutput::Trailing),
+            "\n    fn main() {} \n\n// end"
+        );
+        assert_eq!(collect(TrimOutput::Both), "fn main() {} \n\n// end");
+    }
+
+    #[test]
+    fn stream_errors_use_the_openai_error_shape() {
+        let err = anyhow!("connection reset").context("chat request failed");
+        let event = |err: &anyhow::Error, detail| -> Value {
+            serde_json::from_str(&stream_error_event(err, detail)).unwrap()
+        };
+        assert_eq!(
+            event(&err, ErrorDetail::Minimal),
+            json!({
+                "error": {
+                    "message": "chat completion failed",
+                    "type": "upstream_error",
+                    "param": null,
+                    "code": null,
+                }
+            })
+        );
+        assert_eq!(
+            event(&err, ErrorDetail::Full)["error"]["message"],
+            "chat request failed: connection reset"
+        );
+
+        let upstream = anyhow::Error::new(UpstreamErrorEvent {
+            message: "Upstream duck.ai error (status 429)".to_owned(),
+            kind: upstream_error_type(r#"{"type":"ERR_CONVERSATION_LIMIT"}"#),
+        });
+        let upstream = event(&upstream, ErrorDetail::Minimal);
+        assert_eq!(
+            upstream["error"]["message"],
+            "Upstream duck.ai error (status 429)"
+        );
+        assert_eq!(upstream["error"]["code"], "ERR_CONVERSATION_LIMIT");
+    }
+
+    #[test]
+    fn mid_stream_error_is_an_error_event_not_content() {
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0);
+        formatter
+            .process_payload(r#"{"action":"success","message":"Hi"}"#)
+            .unwrap();
+        let chunks = formatter
+            .process_payload(r#"{"action":"error","type":"ERR_CONVERSATION_LIMIT"}"#)
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        let event: Value = serde_json::from_str(&chunks[0]).unwrap();
+        assert!(event.get("choices").is_none());
+        assert_eq!(event["error"]["type"], "upstream_error");
+        assert_eq!(event["error"]["code"], "ERR_CONVERSATION_LIMIT");
+        assert!(formatter.finish_chunk("stop").is_none());
+        assert_eq!(formatter.collected_text(), "");
+        assert!(formatter
+            .process_payload(r#"{"action":"success","message":"more"}"#)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn citations_are_normalised_and_sent_once() {
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0)
+            .with_citations(true)
+            .collecting();
+        let payload = json!({
+            "action": "success",
+            "message": "See",
+            "sources": ["https://a.example", { "link": "https://b.example", "title": "B" }, 7],
+        })
+        .to_string();
+        let chunks = formatter.process_payload(&payload).unwrap();
+        assert_eq!(chunks.len(), 3);
+        let delta: Value = serde_json::from_str(&chunks[1]).unwrap();
+        assert_eq!(
+            delta["choices"][0]["delta"]["citations"],
+            json!([
+                { "url": "https://a.example", "title": null },
+                { "url": "https://b.example", "title": "B" },
+            ])
+        );
+
+        // Repeated sources are not sent again.
+        assert_eq!(formatter.process_payload(&payload).unwrap().len(), 1);
+        assert_eq!(formatter.citations().unwrap().len(), 2);
+
+        let mut plain = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0);
+        assert_eq!(plain.process_payload(&payload).unwrap().len(), 2);
+        assert!(plain.citations().is_none());
+    }
+
+    #[test]
+    fn json_mode_emits_one_complete_value() {
+        let request = request_from(json!({
+            "messages": [{ "role": "user", "content": "json please" }],
+            "response_format": { "type": "json_object" },
+        }));
+        assert!(request.wants_json());
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0)
+            .with_json_capture(request.wants_json())
+            .collecting();
+        for message in ["Sure:\n```json\n{\"ok\":", " true}", "\n```"] {
+            let payload = json!({ "action": "success", "message": message }).to_string();
+            formatter.process_payload(&payload).unwrap();
+        }
+        assert!(formatter.flush_buffered().is_empty());
+        assert_eq!(formatter.collected_text(), r#"{"ok": true}"#);
+
+        assert_eq!(
+            rejected_param(json!({
+                "messages": [{ "role": "user", "content": "hi" }],
+                "response_format": { "type": "yaml" },
+            }))
+            .as_deref(),
+            Some("response_format")
+        );
+    }
+
+    #[tokio::test]
+    async fn error_event_in_a_200_body_is_an_upstream_error() {
+        const BODY: &str = concat!(
+            "data: {\"action\":\"error\",\"status\":429,\"type\":\"ERR_CONVERSATION_LIMIT\"}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let (raw_tx, raw_rx) = mpsc::channel(8);
+        let mut parser = crate::sse::SseParser::default();
+        for payload in parser.push(BODY.as_bytes()) {
+            raw_tx.send(payload).await.unwrap();
+        }
+        drop(raw_tx);
+        let formatter = collect_formatted(
+            StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0).collecting(),
+            raw_rx,
+        )
+        .await;
+
+        let err = formatter.check_upstream_error().expect_err("error event");
+        assert_eq!(err.status, StatusCode::BAD_GATEWAY);
+        assert_eq!(err.body.error.error_type, "upstream_error");
+        assert_eq!(
+            err.body.error.code.as_deref(),
+            Some("ERR_CONVERSATION_LIMIT")
+        );
+
+        let clean = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0);
+        assert!(clean.check_upstream_error().is_ok());
+    }
+
+    #[test]
+    fn streamed_role_ignores_upstream_role() {
+        let role_of = |formatter: &mut StreamFormatter| {
+            let payload = r#"{"action":"success","role":"user","message":"hi"}"#;
+            let chunk: Value =
+                serde_json::from_str(&formatter.process_payload(payload).unwrap()[0]).unwrap();
+            chunk["choices"][0]["delta"]["role"].clone()
+        };
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0);
+        assert_eq!(role_of(&mut formatter), DEFAULT_RESPONSE_ROLE);
+        assert_eq!(
+            <CliArgs as clap::Parser>::parse_from(["duckai-cli"]).response_role,
+            DEFAULT_RESPONSE_ROLE
+        );
+
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0)
+            .with_role(Arc::from("bot"));
+        assert_eq!(role_of(&mut formatter), "bot");
+        let eager: Value = serde_json::from_str(&formatter.eager_chunks()[0]).unwrap();
+        assert_eq!(eager["choices"][0]["delta"]["role"], "bot");
+    }
+
+    #[test]
+    fn deterministic_ids_count_up() {
+        let ids = CompletionIds::new(true);
+        assert_eq!(ids.next(), "chatcmpl-00000000-0000-0000-0000-000000000001");
+        assert_eq!(ids.next(), "chatcmpl-00000000-0000-0000-0000-000000000002");
+        assert_ne!(
+            CompletionIds::new(false).next(),
+            CompletionIds::new(false).next()
+        );
+    }
+
+    #[tokio::test]
+    async fn router_serves_exactly_the_advertised_endpoints() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let router = routes().with_state(mock_state(&[]));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = reqwest::Client::new();
+        let unique: HashSet<&str> = ENDPOINTS.iter().map(|(_, path, _)| *path).collect();
+        assert_eq!(unique.len(), ENDPOINTS.len());
+        for (method, path, _) in ENDPOINTS {
+            let url = format!(
+                "{base}{}",
+                path.replace(":model_id", model::DEFAULT_MODEL_ID)
+            );
+            let (method, other) = match *method {
+                "GET" => (reqwest::Method::GET, reqwest::Method::POST),
+                "POST" => (reqwest::Method::POST, reqwest::Method::GET),
+                method => panic!("unexpected method {method}"),
+            };
+            let served = client.request(method, &url).send().await.unwrap().status();
+            assert!(
+                !matches!(served.as_u16(), 404 | 405),
+                "{path} answered {served}"
+            );
+            let wrong = client.request(other, &url).send().await.unwrap().status();
+            assert_eq!(wrong.as_u16(), 405, "{path}");
+        }
+        let version: Value = client
+            .get(format!("{base}/v1/version"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(version["endpoints"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("GET /admin/stats")));
+    }
+
+    #[test]
+    fn context_budget_drops_oldest_turns_first() {
+        let turn = "x".repeat(40 * util::CHARS_PER_TOKEN);
+        let messages = json!([
+            { "role": "system", "content": turn },
+            { "role": "user", "content": turn },
+            { "role": "assistant", "content": turn },
+            { "role": "user", "content": turn },
+            { "role": "assistant", "content": turn },
+            { "role": "user", "content": "latest" },
+        ]);
+        let roles = |request: &ChatCompletionRequest| -> Vec<String> {
+            request.messages.iter().map(|m| m.role.clone()).collect()
+        };
+
+        // Each 40-token turn costs a few tokens more once its role label is added.
+        let mut request = request_from(json!({ "messages": messages }));
+        assert_eq!(request.fit_context_budget(1_000), 0);
+        assert_eq!(request.fit_context_budget(150), 1);
+        assert_eq!(roles(&request), ["system", "user", "assistant", "user"]);
+
+        // Never drops the system prompt or the latest user turn, even over budget.
+        assert_eq!(request.fit_context_budget(1), 1);
+        assert_eq!(roles(&request), ["system", "user"]);
+        assert_eq!(request.messages[1].text(), "latest");
+        assert_eq!(request.fit_context_budget(1), 0);
+    }
+
+    #[test]
+    fn context_budget_keeps_tool_results_with_their_call() {
+        let turn = "x".repeat(40 * util::CHARS_PER_TOKEN);
+        let mut request = request_from(json!({
+            "messages": [
+                { "role": "assistant", "content": turn },
+                { "role": "user", "content": turn },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "lookup", "arguments": "{}" },
+                    }],
+                },
+                { "role": "tool", "tool_call_id": "call_1", "content": turn },
+                { "role": "user", "content": turn },
+                { "role": "assistant", "content": turn },
+                { "role": "user", "content": "latest" },
+            ]
+        }));
+        // The orphaned leading reply goes first, then the whole tool-calling turn.
+        assert_eq!(request.fit_context_budget(100), 2);
+        let roles: Vec<&str> = request.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "user"]);
+    }
+
+    #[test]
+    fn caps_each_message_with_an_ellipsis() {
+        let mut request = request_from(json!({
+            "messages": [
+                { "role": "system", "content": "short" },
+                { "role": "user", "content": "héllo world" },
+            ]
+        }));
+        assert!(!request.cap_message_lengths(20));
+        assert!(request.cap_message_lengths(5));
+        assert_eq!(request.messages[0].text(), "short");
+        assert_eq!(request.messages[1].text(), "héllo…");
+    }
+
+    #[test]
+    fn logit_bias_bans_literal_strings_only() {
+        let request = request_from(json!({
+            "messages": [{ "role": "user", "content": "hi" }],
+            "logit_bias": { "darn": -100, "50256": -50, "maybe": -50 }
+        }));
+        assert!(request.validate().is_ok());
+        let banned = request.banned_patterns();
+        assert_eq!(banned.len(), 1);
+
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0)
+            .with_banned(banned)
+            .collecting();
+        formatter
+            .process_payload(r#"{"action":"success","message":"oh darn, maybe"}"#)
+            .unwrap();
+        assert_eq!(formatter.collected_text(), "oh , maybe");
+
+        assert_eq!(
+            rejected_param(json!({
+                "messages": [{ "role": "user", "content": "hi" }],
+                "logit_bias": { "x": -101 }
+            }))
+            .as_deref(),
+            Some("logit_bias")
+        );
+        assert_eq!(
+            rejected_param(json!({
+                "messages": [{ "role": "user", "content": "hi" }],
+                "logit_bias": { "50256": -100 }
+            }))
+            .as_deref(),
+            Some("logit_bias")
+        );
+    }
+
+    #[test]
+    fn tool_turns_round_trip_as_labelled_text() {
+        let request = request_from(json!({
+            "parallel_tool_calls": false,
+            "messages": [
+                { "role": "user", "content": "weather in Paris?" },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "get_weather", "arguments": "{\"city\":\"Paris\"}" }
+                    }]
+                },
+                { "role": "tool", "tool_call_id": "call_1", "content": "18C, sunny" },
+            ]
+        }));
+        assert!(request.reject_unknown_fields().is_ok());
+        assert_eq!(
+            conversation_messages(&request.messages)[1..],
+            [
+                ChatMessage::new(
+                    ChatRole::Assistant,
+                    "[tool call get_weather({\"city\":\"Paris\"})]"
+                ),
+                ChatMessage::user("Tool result (call_1): 18C, sunny"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn accepted_connections_inherit_listener_keepalive() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = bind_listener(addr, Some(Duration::from_secs(30))).expect("bind");
+        let local = listener.local_addr().unwrap();
+        let _client = tokio::net::TcpStream::connect(local)
+            .await
+            .expect("connect");
+        let (accepted, _) = listener.accept().await.expect("accept");
+        assert!(socket2::SockRef::from(&accepted).keepalive().unwrap());
+
+        let plain = bind_listener(addr, None).expect("bind");
+        assert!(!socket2::SockRef::from(&plain).keepalive().unwrap());
+    }
+
+    #[tokio::test]
+    async fn waiting_for_upstream_gives_up_after_timeout() {
+        let mut config = crate::transport::testing::test_config();
+        config.pinned_cert = Some(std::path::PathBuf::from("/nonexistent/duckai.pem"));
+        let sessions = SessionCache::new(config.clone(), Duration::from_secs(60));
+        let err = wait_for_upstream(&sessions, Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("still unreachable"));
+
+        config.pinned_cert = None;
+        config.mock = Some(crate::mock::MockConfig::default());
+        let sessions = SessionCache::new(config, Duration::from_secs(60));
+        assert!(wait_for_upstream(&sessions, Duration::ZERO).await.is_ok());
+    }
+
+    #[test]
+    fn pretty_json_follows_query_or_header() {
+        let mut headers = HeaderMap::new();
+        assert!(!wants_pretty(&PrettyQuery::default(), &headers));
+        assert!(wants_pretty(&PrettyQuery { pretty: true }, &headers));
+        headers.insert("x-pretty", HeaderValue::from_static("TRUE"));
+        assert!(wants_pretty(&PrettyQuery::default(), &headers));
+        headers.insert("x-pretty", HeaderValue::from_static("no"));
+        assert!(!wants_pretty(&PrettyQuery::default(), &headers));
+    }
+
+    #[test]
+    fn strip_patterns_only_rewrite_the_opening_of_a_stream() {
+        let patterns: Arc<[Regex]> = Arc::from([Regex::new(r"(?i)disclaimer:[^\n]*\n").unwrap()]);
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0)
+            .with_strip_patterns(patterns.clone())
+            .collecting();
+        for message in [
+            "Disclaimer: ",
+            "AI may err.\n",
+            "Hi\n",
+            "disclaimer: kept\n",
+        ] {
+            let payload = json!({ "action": "success", "message": message }).to_string();
+            formatter.process_payload(&payload).unwrap();
+        }
+        assert_eq!(formatter.collected_text(), "Hi\ndisclaimer: kept\n");
+        assert_eq!(
+            strip_boilerplate(&patterns, "Disclaimer: x\nHi disclaimer: y\n"),
+            "Hi "
+        );
+
+        let mut short = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0)
+            .with_strip_patterns(patterns)
+            .collecting();
+        short
+            .process_payload(r#"{"action":"success","message":"Short"}"#)
+            .unwrap();
+        assert!(!short.has_content());
+        assert_eq!(short.flush_buffered().len(), 1);
+        assert_eq!(short.collected_text(), "Short");
+
+        // The flushed opening still honours max_tokens and --trim-output.
+        let mut limited = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0)
+            .with_strip_patterns(Arc::from([Regex::new("^Note: ").unwrap()]))
+            .with_trim(TrimOutput::Both)
+            .with_token_limit(Some(1))
+            .collecting();
+        limited
+            .process_payload(r#"{"action":"success","message":"Note: abcdefgh  "}"#)
+            .unwrap();
+        let chunks = limited.flush_buffered();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(limited.collected_text(), "abcd");
+        assert_eq!(limited.finish_reason(), Some("length"));
+    }
+
+    #[test]
+    fn eager_chunks_precede_content_without_counting_as_it() {
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0);
+        let eager = formatter.eager_chunks();
+        assert_eq!(eager.len(), 2);
+        let empty: Value = serde_json::from_str(&eager[1]).unwrap();
+        assert_eq!(empty["choices"][0]["delta"]["content"], "");
+        assert!(!formatter.has_content());
+
+        let chunks = formatter
+            .process_payload(r#"{"action":"success","role":"assistant","message":"Hi"}"#)
+            .unwrap();
+        assert_eq!(chunks.len(), 1, "role chunk is not repeated");
+    }
+
+    #[tokio::test]
+    async fn stalled_client_times_out_instead_of_blocking() {
+        let (sender, mut receiver) = mpsc::channel::<String>(1);
+        let timeout = Some(Duration::from_millis(20));
+        assert_eq!(
+            send_to_client(&sender, "a".to_owned(), timeout).await,
+            Delivery::Sent
+        );
+        assert_eq!(
+            send_to_client(&sender, "b".to_owned(), timeout).await,
+            Delivery::Stalled
+        );
+        assert_eq!(receiver.recv().await.as_deref(), Some("a"));
+        drop(receiver);
+        assert_eq!(
+            send_to_client(&sender, "c".to_owned(), None).await,
+            Delivery::Closed
+        );
+    }
+
+    fn mock_state(extra: &[&str]) -> ServerState {
+        let args = <CliArgs as clap::Parser>::parse_from(
+            ["duckai-cli", "--serve", "--mock"].iter().chain(extra),
+        );
+        let sessions = Arc::new(SessionCache::new(args.session_config(), args.vqd_ttl()));
+        let allowed_models = model::models().iter().map(|m| m.id).collect();
+        ServerState::new(&args, sessions, allowed_models).expect("state")
+    }
+
+    #[test]
+    fn admin_endpoints_need_some_configured_key() {
+        let bearer = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, format!("Bearer {key}").parse().unwrap());
+            headers
+        };
+        let status = |state: &ServerState, headers: &HeaderMap| {
+            authorize_admin(state, headers).err().map(|err| err.status)
+        };
+
+        let open = mock_state(&[]);
+        assert!(authorize(&open, &HeaderMap::new()).is_ok());
+        assert_eq!(
+            status(&open, &HeaderMap::new()),
+            Some(StatusCode::FORBIDDEN)
+        );
+
+        let shared = mock_state(&["--server-api-key", "user"]);
+        assert_eq!(status(&shared, &bearer("user")), None);
+        assert_eq!(
+            status(&shared, &HeaderMap::new()),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+
+        let admin = mock_state(&["--server-api-key", "user", "--admin-api-key", "root"]);
+        assert_eq!(status(&admin, &bearer("root")), None);
+        assert_eq!(
+            status(&admin, &bearer("user")),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+        assert!(authorize(&admin, &bearer("user")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn rendered_prompt_is_echoed_only_when_asked_and_never_streamed() {
+        let request = |stream: bool| -> ChatCompletionRequest {
+            serde_json::from_value(json!({
+                "messages": [{ "role": "user", "content": "echo me" }],
+                "stream": stream,
+            }))
+            .unwrap()
+        };
+
+        let state = mock_state(&[]);
+        let Ok((response, _)) = chat_completions_non_stream(&state, request(false)).await else {
+            panic!("mock completion failed");
+        };
+        assert!(response.debug.is_none());
+        assert!(serde_json::to_value(&response)
+            .unwrap()
+            .get("_debug")
+            .is_none());
+
+        let state = mock_state(&["--debug-echo-prompt"]);
+        let Ok((response, _)) = chat_completions_non_stream(&state, request(false)).await else {
+            panic!("mock completion failed");
+        };
+        let rendered = serde_json::to_value(&response).unwrap()["_debug"]["rendered_prompt"]
+            .as_str()
+            .map(str::to_owned);
+        assert!(rendered.is_some_and(|prompt| prompt.contains("echo me")));
+
+        let streamed = chat_completions_stream(state, request(true)).await;
+        let body = axum::body::to_bytes(streamed.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("Hello"));
+        assert!(!body.contains("rendered_prompt"));
+        assert!(!body.contains("_debug"));
+    }
+
+    #[test]
+    fn empty_allowed_models_fail_startup() {
+        let err = check_allowed_models(&HashSet::new()).unwrap_err();
+        assert!(err.to_string().contains(model::DEFAULT_MODEL_ID));
+        assert!(check_allowed_models(&HashSet::from([model::DEFAULT_MODEL_ID])).is_ok());
+    }
+
+    #[test]
+    fn server_timing_lists_each_phase() {
+        let meta = CompletionMeta {
+            session_prep: Duration::from_millis(1200),
+            upstream: Duration::from_millis(800),
+            vqd: Arc::new(crate::mock::vqd_session()),
+        };
+        assert_eq!(
+            server_timing(&meta, Duration::from_millis(2100)),
+            "vqd;dur=1200, upstream;dur=800, total;dur=2100"
+        );
+    }
+
+    #[test]
+    fn final_stream_chunk_reports_timings() {
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0);
+        formatter.start_upstream_timer();
+        let chunk: Value =
+            serde_json::from_str(&formatter.finish_chunk("stop").expect("final chunk")).unwrap();
+        assert!(chunk["timings"]["upstream_ms"].is_u64());
+        assert!(chunk["timings"]["total_ms"].is_u64());
+        assert!(formatter.finish_chunk("stop").is_none());
+    }
+
+    #[test]
+    fn renders_developer_messages_without_counting_as_user() {
+        let messages: Vec<IncomingMessage> = serde_json::from_value(json!([
+            { "role": "developer", "content": "answer in French" },
+            { "role": "user", "content": "hi" },
+        ]))
+        .unwrap();
+        assert_eq!(
+            render_conversation(&messages).ok().as_deref(),
+            Some("Developer: answer in French\n\nUser: hi")
+        );
+
+        let only_developer: Vec<IncomingMessage> =
+            serde_json::from_value(json!([{ "role": "developer", "content": "rules" }])).unwrap();
+        assert!(render_conversation(&only_developer).is_err());
+    }
+
+    #[test]
+    fn maps_each_openai_role_to_an_upstream_message() {
+        let messages: Vec<IncomingMessage> = serde_json::from_value(json!([
+            { "role": "system", "content": "be brief" },
+            { "role": "developer", "content": "answer in French" },
+            { "role": "user", "content": "hi" },
+            { "role": "assistant", "content": "bonjour" },
+            { "role": "user", "content": "" },
+            { "role": "user", "content": "again" },
+        ]))
+        .unwrap();
+        assert_eq!(
+            conversation_messages(&messages),
+            [
+                ChatMessage::user("System: be brief"),
+                ChatMessage::user("Developer: answer in French"),
+                ChatMessage::user("hi"),
+                ChatMessage::new(ChatRole::Assistant, "bonjour"),
+                ChatMessage::user("again"),
+            ]
+        );
+    }
+
+    #[test]
+    fn message_limit_excludes_system_messages() {
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "messages": [
+                { "role": "system", "content": "be brief" },
+                { "role": "developer", "content": "no lists" },
+                { "role": "user", "content": "hi" },
+                { "role": "assistant", "content": "hello" },
+                { "role": "user", "content": "again" },
+            ],
+        }))
+        .unwrap();
+        assert!(request.check_message_limit(3).is_ok());
+        let err = request.check_message_limit(2).unwrap_err();
+        assert_eq!(err.body.error.param.as_deref(), Some("messages"));
+    }
+
+    #[test]
+    fn json_accept_header_selects_stream_fallback() {
+        let mut headers = HeaderMap::new();
+        assert!(!prefers_json(&headers));
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        assert!(prefers_json(&headers));
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("text/event-stream, application/json"),
+        );
+        assert!(!prefers_json(&headers));
+    }
+
+    #[test]
+    fn timeout_header_is_bounded_by_the_server_maximum() {
+        let max = Duration::from_secs(120);
+        let mut headers = HeaderMap::new();
+        assert_eq!(requested_timeout(&headers, max).ok(), Some(None));
+
+        headers.insert(TIMEOUT_HEADER, HeaderValue::from_static("90"));
+        assert_eq!(
+            requested_timeout(&headers, max).ok(),
+            Some(Some(Duration::from_secs(90)))
+        );
+
+        for rejected in ["121", "0", "soon"] {
+            headers.insert(TIMEOUT_HEADER, HeaderValue::from_static(rejected));
+            let err = requested_timeout(&headers, max).expect_err(rejected);
+            assert_eq!(err.status, StatusCode::BAD_REQUEST);
+            assert_eq!(err.body.error.param.as_deref(), Some("X-DuckAI-Timeout"));
+        }
+    }
+
+    #[test]
+    fn strict_mode_lists_unknown_fields() {
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gpt-5-mini",
+            "temprature": 0.5,
+            "messages": [{ "role": "user", "content": "hi", "nme": "bob" }],
+        }))
+        .unwrap();
+        let err = request.reject_unknown_fields().unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.body.error.param.as_deref(), Some("temprature"));
+        assert!(err.body.error.message.contains("messages[0].nme"));
+
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "messages": [{ "role": "user", "content": "hi" }],
+            "stream": true,
+        }))
+        .unwrap();
+        assert!(request.reject_unknown_fields().is_ok());
+    }
+
+    #[test]
+    fn challenge_pending_is_forwarded_to_stream_clients() {
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0);
+        let chunks = formatter
+            .process_payload(r#"{"action":"challenge_pending","url":"http://127.0.0.1:9"}"#)
+            .unwrap();
+        let event: Value = serde_json::from_str(&chunks[0]).unwrap();
+        assert_eq!(event["type"], "challenge_pending");
+        assert_eq!(event["url"], "http://127.0.0.1:9");
+        assert!(!formatter.has_content());
+    }
+
+    #[test]
+    fn detects_empty_completion() {
+        let mut formatter = StreamFormatter::new("id".to_owned(), "gpt-5-mini".to_owned(), 0);
+        let chunks = formatter
+            .process_payload(r#"{"action":"success","role":"assistant","message":""}"#)
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert!(!formatter.has_content());
+        formatter
+            .process_payload(r#"{"action":"success","message":"Hi"}"#)
+            .unwrap();
+        assert!(formatter.has_content());
+    }
+
+    #[test]
+    fn both_token_limit_fields_truncate_alike() {
+        let messages = json!([{ "role": "user", "content": "hi" }]);
+        let legacy = request_from(json!({ "messages": messages, "max_tokens": 2 }));
+        let current = request_from(json!({ "messages": messages, "max_completion_tokens": 2 }));
+        let both = request_from(json!({
+            "messages": messages,
+            "max_tokens": 100,
+            "max_completion_tokens": 2,
+        }));
+
+        let expected = ("Hello th".to_owned(), "length");
+        for request in [&legacy, &current, &both] {
+            let mut formatter = StreamFormatter::new("id".to_owned(), "m".to_owned(), 0)
+                .with_token_limit(request.completion_token_limit())
+                .collecting();
+            let mut streamed = String::new();
+            let mut finish = None;
+            for part in ["Hello ", "there, ", "world"] {
+                let payload = json!({ "action": "success", "message": part }).to_string();
+                for chunk in formatter.process_payload(&payload).unwrap() {
+                    let chunk: Value = serde_json::from_str(&chunk).unwrap();
+                    let choice = &chunk["choices"][0];
+                    if let Some(content) = choice["delta"]["content"].as_str() {
+                        streamed.push_str(content);
+                    }
+                    if let Some(reason) = choice["finish_reason"].as_str() {
+                        finish = Some(reason.to_owned());
+                    }
+                }
+            }
+            assert_eq!(streamed, expected.0);
+            assert_eq!(finish.as_deref(), Some("length"));
+            assert_eq!(formatter.collected_text(), expected.0);
+            assert_eq!(formatter.finish_reason(), Some(expected.1));
+        }
+    }
+
+    #[test]
+    fn minimal_error_detail_hides_upstream_body() {
+        let body = "internal stack trace".to_owned();
+        let minimal = ApiError::upstream(500, body.clone(), ErrorDetail::Minimal);
+        assert_eq!(minimal.status, StatusCode::BAD_GATEWAY);
+        assert!(!minimal.body.error.message.contains("stack trace"));
+
+        let full = ApiError::upstream(429, body, ErrorDetail::Full);
+        assert_eq!(full.status, StatusCode::BAD_REQUEST);
+        assert!(full.body.error.message.contains("stack trace"));
+    }
+
+    #[test]
+    fn logprobs_mode_controls_reported_value() {
+        let request = request_from(json!({
+            "messages": [{ "role": "user", "content": "hi" }],
+            "logprobs": true,
+        }));
+        let err = request.logprobs_value(LogprobsMode::Error).unwrap_err();
+        assert_eq!(err.body.error.param.as_deref(), Some("logprobs"));
+        assert_eq!(
+            request.logprobs_value(LogprobsMode::Empty).ok(),
+            Some(json!({ "content": [] }))
+        );
+        assert_eq!(
+            request.logprobs_value(LogprobsMode::Null).ok(),
+            Some(Value::Null)
+        );
+
+        let plain = request_from(json!({ "messages": [{ "role": "user", "content": "hi" }] }));
+        assert_eq!(
+            plain.logprobs_value(LogprobsMode::Error).ok(),
+            Some(Value::Null)
+        );
+    }
 }
@@ -1,21 +1,250 @@
-use std::time::Duration;
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use anyhow::{anyhow, Context};
 use reqwest::header::{
     HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE, ORIGIN, REFERER, USER_AGENT,
 };
-use reqwest::{Client, ClientBuilder, Url};
+use reqwest::{Certificate, ClientBuilder, Proxy, Url};
 
+use crate::challenge::ChallengeConfig;
+use crate::cookies::CookieJar;
 use crate::error::Result;
-use crate::util::{platform_token, sec_ch_ua};
+use crate::har::{HarRecorder, HarTransport};
+use crate::mock::MockConfig;
+use crate::transport::{ReqwestTransport, Transport, TransportResponse};
+use crate::util::{
+    platform_token, sec_ch_ua, sec_ch_ua_arch, sec_ch_ua_bitness, sec_ch_ua_full_version_list,
+};
+
+pub const BASE_URL: &str = "https://duckduckgo.com";
+pub const DEFAULT_API_VERSION: &str = "v1";
+
+/// Default cap on how much of a non-SSE upstream body is read.
+pub const DEFAULT_MAX_ERROR_BODY: usize = 64 * 1024;
+
+/// Longest wait between two attempts, however many retries came before.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Reads a PEM or DER certificate for `--pin-cert`.
+fn load_certificate(path: &Path) -> Result<Certificate> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("reading pinned certificate {}", path.display()))?;
+    let certificate = if bytes.starts_with(b"-----BEGIN") {
+        Certificate::from_pem(&bytes)
+    } else {
+        Certificate::from_der(&bytes)
+    };
+    certificate.map_err(|err| {
+        anyhow!(
+            "pinned certificate {} is not a valid PEM or DER certificate: {err}",
+            path.display()
+        )
+    })
+}
+
+/// Checks that `url` can serve as the Duck.ai base and gives it a trailing slash, so
+/// relative endpoint paths are joined under it rather than replacing its last segment.
+pub fn normalize_base_url(url: &Url) -> Result<Url> {
+    if url.cannot_be_a_base() || !matches!(url.scheme(), "http" | "https") {
+        return Err(anyhow!(
+            "base URL `{url}` must be an absolute http(s) URL such as https://duckduckgo.com"
+        ));
+    }
+    let mut url = url.clone();
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+/// Retries for transient upstream failures (`--max-retries`, `--retry-base-delay-ms`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts made after the first one; zero disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for each one after it.
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            base_delay: Duration::from_millis(250),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before retry number `retry` (zero-based): the doubled base delay, capped at
+    /// [`MAX_RETRY_DELAY`] and scaled by a random 50-100% so clients do not retry in step.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(MAX_RETRY_DELAY);
+        // Clock-derived jitter is enough to spread retries; nothing here needs real
+        // randomness. The digits above the microseconds are used because some clocks
+        // (macOS among them) only tick in whole microseconds.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos())
+            .unwrap_or_default();
+        backoff.mul_f64(0.5 + f64::from(nanos / 1000 % 1000) / 2000.0)
+    }
+}
+
+/// Runs `attempt` until it succeeds, fails permanently, or `policy` runs out of retries,
+/// sleeping with jittered exponential backoff in between. `is_transient` decides which
+/// outcomes are retried; the last outcome is returned as is.
+pub async fn retry_with_backoff<T, F, Fut>(
+    policy: RetryPolicy,
+    what: &str,
+    mut attempt: F,
+    is_transient: impl Fn(&Result<T>) -> bool,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut retry = 0;
+    loop {
+        let outcome = attempt().await;
+        if retry >= policy.max_retries || !is_transient(&outcome) {
+            return outcome;
+        }
+        let delay = policy.delay(retry);
+        retry += 1;
+        match &outcome {
+            Ok(_) => tracing::warn!(
+                retry,
+                delay_ms = delay.as_millis() as u64,
+                "{what} got a retryable status; retrying"
+            ),
+            Err(err) => tracing::warn!(
+                retry,
+                delay_ms = delay.as_millis() as u64,
+                "{what} failed ({err}); retrying"
+            ),
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Transient outcomes of an upstream request: connection and timeout failures, and HTTP
+/// 429 or 5xx. Other 4xx statuses and request-building errors are final.
+pub fn transient_response(outcome: &Result<TransportResponse>) -> bool {
+    match outcome {
+        Ok(response) => response.status == 429 || (500..600).contains(&response.status),
+        Err(err) => err.chain().any(|cause| {
+            cause.is::<std::io::Error>()
+                || cause
+                    .downcast_ref::<reqwest::Error>()
+                    .is_some_and(|err| !err.is_builder())
+        }),
+    }
+}
+
+/// Enforces a minimum gap between chat requests sent on one session.
+#[derive(Debug)]
+pub struct RequestThrottle {
+    interval: Duration,
+    last: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl RequestThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: tokio::sync::Mutex::new(None),
+        }
+    }
 
-const BASE_URL: &str = "https://duckduckgo.com";
+    /// Waits until `interval` has passed since the previous request. Waiters queue on the
+    /// lock, so concurrent callers are spaced out rather than released together.
+    pub async fn wait(&self) {
+        let mut last = self.last.lock().await;
+        if let Some(previous) = *last {
+            let elapsed = previous.elapsed();
+            if elapsed < self.interval {
+                tokio::time::sleep(self.interval - elapsed).await;
+            }
+        }
+        *last = Some(Instant::now());
+    }
+}
+
+/// HTTP protocol used for upstream connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// Let TLS ALPN pick HTTP/2 when the server offers it, else HTTP/1.1.
+    #[default]
+    Negotiate,
+    Http1,
+    /// HTTP/2 with prior knowledge, skipping negotiation.
+    Http2,
+}
+
+impl HttpVersion {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Negotiate => "negotiate",
+            Self::Http1 => "http1",
+            Self::Http2 => "http2",
+        }
+    }
+}
+
+/// Duck.ai endpoint paths, relative to the base URL.
+#[derive(Debug, Clone)]
+pub struct ApiPaths {
+    pub chat: String,
+    pub status: String,
+    pub models: String,
+}
+
+impl ApiPaths {
+    /// Standard `duckchat/<version>/...` paths for the given API version.
+    pub fn for_version(version: &str) -> Self {
+        Self {
+            chat: format!("duckchat/{version}/chat"),
+            status: format!("duckchat/{version}/status"),
+            models: format!("duckchat/{version}/models"),
+        }
+    }
+}
+
+impl Default for ApiPaths {
+    fn default() -> Self {
+        Self::for_version(DEFAULT_API_VERSION)
+    }
+}
 
 /// Wrapper around the configured HTTP client and session metadata.
 #[derive(Debug, Clone)]
 pub struct HttpSession {
-    client: Client,
+    transport: Arc<dyn Transport>,
     base: Url,
     user_agent: String,
+    challenge: ChallengeConfig,
+    paths: ApiPaths,
+    default_temperature: Option<f64>,
+    fe_signals: bool,
+    challenge_log_dir: Option<PathBuf>,
+    max_error_body: usize,
+    accept_invalid_fe_version: bool,
+    throttle: Option<Arc<RequestThrottle>>,
+    retry: RetryPolicy,
+    /// Cookie store shared with the client; `None` when cookies are disabled.
+    cookie_jar: Option<Arc<CookieJar>>,
+    mock_response: Option<Arc<str>>,
+    js_cancel: Arc<AtomicBool>,
 }
 
 /// Minimal data required to build an HTTP session.
@@ -23,6 +252,45 @@ pub struct HttpSession {
 pub struct SessionConfig {
     pub user_agent: String,
     pub timeout: Duration,
+    /// Duck.ai origin every endpoint path is joined against.
+    pub base_url: Url,
+    pub challenge: ChallengeConfig,
+    pub debug_connections: bool,
+    pub full_client_hints: bool,
+    pub paths: ApiPaths,
+    /// Temperature sent when a chat request does not specify one.
+    pub default_temperature: Option<f64>,
+    /// Certificate (PEM or DER) that replaces the built-in trust roots.
+    pub pinned_cert: Option<PathBuf>,
+    /// Skip upstream TLS certificate verification entirely.
+    pub accept_invalid_certs: bool,
+    /// Local bind address; an unspecified IPv4 or IPv6 address restricts the IP family.
+    pub local_address: Option<IpAddr>,
+    /// Keep cookies set by Duck.ai across requests.
+    pub cookies: bool,
+    pub http_version: HttpVersion,
+    /// Proxy for every upstream request (`http://`, `https://` or `socks5://`). When unset,
+    /// the standard `HTTPS_PROXY`/`HTTP_PROXY`/`ALL_PROXY` variables apply.
+    pub proxy: Option<String>,
+    /// Send the `x-fe-signals` header with chat requests.
+    pub fe_signals: bool,
+    /// Directory receiving a copy of every raw challenge payload.
+    pub challenge_log_dir: Option<PathBuf>,
+    /// Bytes of a non-SSE upstream body read before the rest is discarded.
+    pub max_error_body: usize,
+    /// Continue with the last known (or an empty) fe-version when the homepage markers
+    /// cannot be found.
+    pub accept_invalid_fe_version: bool,
+    /// Minimum gap between chat requests on a session.
+    pub min_request_interval: Option<Duration>,
+    /// Backoff for transient failures of the status, homepage and chat requests.
+    pub retry: RetryPolicy,
+    /// Records every upstream exchange as HAR; shared by all sessions built from this config.
+    pub har: Option<Arc<HarRecorder>>,
+    pub mock: Option<MockConfig>,
+    /// Set on shutdown so in-flight JS evaluations stop instead of running to their
+    /// deadline; shared by all sessions built from this config.
+    pub js_cancel: Arc<AtomicBool>,
 }
 
 impl SessionConfig {
@@ -30,6 +298,27 @@ impl SessionConfig {
         Self {
             user_agent,
             timeout,
+            base_url: Url::parse(BASE_URL).expect("BASE_URL is a valid URL"),
+            challenge: ChallengeConfig::default(),
+            debug_connections: false,
+            full_client_hints: false,
+            paths: ApiPaths::default(),
+            default_temperature: None,
+            pinned_cert: None,
+            accept_invalid_certs: false,
+            local_address: None,
+            cookies: true,
+            http_version: HttpVersion::default(),
+            proxy: None,
+            fe_signals: true,
+            challenge_log_dir: None,
+            max_error_body: DEFAULT_MAX_ERROR_BODY,
+            accept_invalid_fe_version: false,
+            min_request_interval: None,
+            retry: RetryPolicy::default(),
+            har: None,
+            mock: None,
+            js_cancel: Arc::new(AtomicBool::new(false)),
         }
     }
 }
@@ -38,6 +327,7 @@ impl HttpSession {
     /// Build a new HTTP session based on CLI arguments.
     pub fn new(config: &SessionConfig) -> Result<Self> {
         let timeout = config.timeout;
+        let base = normalize_base_url(&config.base_url)?;
 
         let mut default_headers = HeaderMap::new();
         default_headers.insert(USER_AGENT, HeaderValue::from_str(&config.user_agent)?);
@@ -54,27 +344,105 @@ impl HttpSession {
             sec_ch_ua_platform_header(),
             HeaderValue::from_str(platform_token(&config.user_agent))?,
         );
-        default_headers.insert(ORIGIN, HeaderValue::from_static(BASE_URL));
-        default_headers.insert(REFERER, HeaderValue::from_static(BASE_URL));
+        if config.full_client_hints {
+            default_headers.insert(
+                HeaderName::from_static("sec-ch-ua-full-version-list"),
+                HeaderValue::from_str(&sec_ch_ua_full_version_list(&config.user_agent))?,
+            );
+            default_headers.insert(
+                HeaderName::from_static("sec-ch-ua-arch"),
+                HeaderValue::from_static(sec_ch_ua_arch(&config.user_agent)),
+            );
+            default_headers.insert(
+                HeaderName::from_static("sec-ch-ua-bitness"),
+                HeaderValue::from_static(sec_ch_ua_bitness(&config.user_agent)),
+            );
+        }
+        let origin = base.origin().ascii_serialization();
+        default_headers.insert(ORIGIN, HeaderValue::from_str(&origin)?);
+        default_headers.insert(REFERER, HeaderValue::from_str(origin.as_str())?);
+
+        let mut builder = ClientBuilder::new();
+        if let Some(path) = &config.pinned_cert {
+            builder = builder
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(load_certificate(path)?);
+        }
+        if config.accept_invalid_certs {
+            tracing::warn!(
+                "TLS certificate verification is DISABLED for upstream requests \
+                 (--allow-insecure-upstream); traffic can be intercepted and modified"
+            );
+            builder = builder.danger_accept_invalid_certs(true);
+        }
 
-        let client = ClientBuilder::new()
-            .cookie_store(true)
+        if let Some(proxy) = &config.proxy {
+            let proxy = Proxy::all(proxy.as_str()).map_err(|err| {
+                anyhow!("invalid --proxy URL (expected e.g. socks5://host:port): {err}")
+            })?;
+            builder = builder.proxy(proxy);
+        }
+        builder = match config.http_version {
+            HttpVersion::Negotiate => builder,
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+        };
+
+        let session_headers = config.har.as_ref().map(|_| default_headers.clone());
+        let cookie_jar = config.cookies.then(|| Arc::new(CookieJar::default()));
+        if let Some(jar) = &cookie_jar {
+            builder = builder.cookie_provider(jar.clone());
+        }
+        let client = builder
+            .local_address(config.local_address)
             .default_headers(default_headers)
             .timeout(timeout)
             .pool_idle_timeout(Duration::from_secs(30))
             .user_agent(&config.user_agent)
+            .connection_verbose(config.debug_connections)
             .build()?;
 
+        let mock_response = match &config.mock {
+            Some(mock) => Some(Arc::from(mock.load_response()?)),
+            None => None,
+        };
+
+        let mut transport: Arc<dyn Transport> = Arc::new(ReqwestTransport::new(client));
+        if let (Some(recorder), Some(headers)) = (&config.har, session_headers) {
+            transport = Arc::new(HarTransport::new(transport, recorder.clone(), headers));
+        }
+
         Ok(Self {
-            client,
-            base: Url::parse(BASE_URL)?,
+            transport,
+            base,
             user_agent: config.user_agent.clone(),
+            challenge: config.challenge.clone(),
+            paths: config.paths.clone(),
+            default_temperature: config.default_temperature,
+            fe_signals: config.fe_signals,
+            challenge_log_dir: config.challenge_log_dir.clone(),
+            max_error_body: config.max_error_body,
+            accept_invalid_fe_version: config.accept_invalid_fe_version,
+            throttle: config
+                .min_request_interval
+                .map(|interval| Arc::new(RequestThrottle::new(interval))),
+            retry: config.retry,
+            cookie_jar,
+            mock_response,
+            js_cancel: config.js_cancel.clone(),
         })
     }
 
-    /// Returns reference to the inner `reqwest::Client`.
-    pub fn client(&self) -> &Client {
-        &self.client
+    /// Transport carrying every upstream request.
+    pub fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    /// Replaces the reqwest transport, e.g. with a scripted one in tests.
+    #[cfg(test)]
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
     }
 
     /// Base DuckDuckGo URL.
@@ -86,6 +454,80 @@ impl HttpSession {
     pub fn user_agent(&self) -> &str {
         &self.user_agent
     }
+
+    /// Temperature used for chat requests that do not set their own.
+    pub fn default_temperature(&self) -> Option<f64> {
+        self.default_temperature
+    }
+
+    /// Whether chat requests carry the `x-fe-signals` header.
+    pub fn fe_signals(&self) -> bool {
+        self.fe_signals
+    }
+
+    /// Where raw challenge payloads are saved, if enabled.
+    pub fn challenge_log_dir(&self) -> Option<&Path> {
+        self.challenge_log_dir.as_deref()
+    }
+
+    /// Bytes of a non-SSE upstream body kept before reading stops.
+    pub fn max_error_body(&self) -> usize {
+        self.max_error_body
+    }
+
+    /// Whether a missing fe-version marker is tolerated.
+    pub fn accept_invalid_fe_version(&self) -> bool {
+        self.accept_invalid_fe_version
+    }
+
+    /// Backoff applied to transient upstream failures.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    /// Waits out `--min-request-interval` before a chat request is sent.
+    pub async fn throttle(&self) {
+        if let Some(throttle) = &self.throttle {
+            throttle.wait().await;
+        }
+    }
+
+    /// Loads cookies saved by an earlier run into the session's jar, dropping expired
+    /// ones. A missing file loads nothing. Returns the number of cookies loaded.
+    pub fn load_cookies(&self, path: &Path) -> Result<usize> {
+        self.cookie_jar()?.load(path)
+    }
+
+    /// Saves the session's unexpired cookies for the next run.
+    pub fn save_cookies(&self, path: &Path) -> Result<()> {
+        self.cookie_jar()?.save(path)
+    }
+
+    fn cookie_jar(&self) -> Result<&CookieJar> {
+        self.cookie_jar
+            .as_deref()
+            .ok_or_else(|| anyhow!("cookies are disabled for this session"))
+    }
+
+    /// Duck.ai endpoint paths.
+    pub fn paths(&self) -> &ApiPaths {
+        &self.paths
+    }
+
+    /// Canned SSE body replayed instead of contacting Duck.ai (mock mode only).
+    pub fn mock_response(&self) -> Option<&str> {
+        self.mock_response.as_deref()
+    }
+
+    /// Cancellation flag handed to JS evaluations for this session.
+    pub fn js_cancel(&self) -> &AtomicBool {
+        &self.js_cancel
+    }
+
+    /// Challenge verification overrides.
+    pub fn challenge_config(&self) -> &ChallengeConfig {
+        &self.challenge
+    }
 }
 
 fn sec_ch_ua_header() -> HeaderName {
@@ -99,3 +541,195 @@ fn sec_ch_ua_mobile_header() -> HeaderName {
 fn sec_ch_ua_platform_header() -> HeaderName {
     HeaderName::from_static("sec-ch-ua-platform")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::transport::testing::{temp_path, test_config};
+    use crate::transport::TransportRequest;
+
+    #[test]
+    fn unloadable_pinned_certificate_is_an_error() {
+        let mut config = test_config();
+        config.pinned_cert = Some(PathBuf::from("/nonexistent/duckai.pem"));
+        let err = HttpSession::new(&config).unwrap_err();
+        assert!(err.to_string().contains("reading pinned certificate"));
+
+        let path = temp_path("pin.pem");
+        std::fs::write(&path, "-----BEGIN CERTIFICATE-----\nnot base64\n").unwrap();
+        config.pinned_cert = Some(path.clone());
+        let result = HttpSession::new(&config);
+        std::fs::remove_file(&path).ok();
+        assert!(result.is_err());
+    }
+
+    /// Minimal keep-alive HTTP/1.1 server that counts accepted TCP connections.
+    async fn spawn_counting_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    loop {
+                        let read = match stream.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => read,
+                        };
+                        buf.extend_from_slice(&chunk[..read]);
+                        while let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                            buf.drain(..end + 4);
+                            let reply = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+                            if stream.write_all(reply).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        (format!("http://{addr}/"), accepted)
+    }
+
+    #[tokio::test]
+    async fn throttle_spaces_out_requests() {
+        let throttle = RequestThrottle::new(Duration::from_millis(50));
+        let started = Instant::now();
+        throttle.wait().await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+        throttle.wait().await;
+        throttle.wait().await;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn base_url_keeps_its_path_prefix() {
+        let base = normalize_base_url(&Url::parse("http://127.0.0.1:9000/mock").unwrap()).unwrap();
+        assert_eq!(
+            base.join(&ApiPaths::default().chat).unwrap().as_str(),
+            "http://127.0.0.1:9000/mock/duckchat/v1/chat"
+        );
+        let root = normalize_base_url(&Url::parse(BASE_URL).unwrap()).unwrap();
+        assert_eq!(root.as_str(), "https://duckduckgo.com/");
+
+        let mut config = test_config();
+        config.base_url = Url::parse("mailto:duck@example.com").unwrap();
+        let err = HttpSession::new(&config).unwrap_err();
+        assert!(err.to_string().contains("absolute http(s) URL"));
+    }
+
+    #[test]
+    fn invalid_proxy_url_is_an_error() {
+        let mut config = test_config();
+        config.proxy = Some("not a url".to_owned());
+        let err = HttpSession::new(&config).unwrap_err();
+        assert!(err.to_string().contains("invalid --proxy URL"));
+
+        config.proxy = Some("socks5://127.0.0.1:1080".to_owned());
+        assert!(HttpSession::new(&config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reuses_connection_across_requests() {
+        let (url, accepted) = spawn_counting_server().await;
+        let mut config = test_config();
+        config.debug_connections = true;
+        let session = HttpSession::new(&config).expect("session");
+
+        for _ in 0..3 {
+            let body = session
+                .transport()
+                .send(TransportRequest::get(Url::parse(&url).expect("url")))
+                .await
+                .expect("request")
+                .text()
+                .await
+                .expect("body");
+            assert_eq!(body, "ok");
+        }
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    fn response(status: u16) -> Result<TransportResponse> {
+        Ok(TransportResponse {
+            status,
+            headers: HeaderMap::new(),
+            body: Box::pin(futures_util::stream::empty()),
+        })
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_with_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(1),
+        };
+        let attempts = AtomicUsize::new(0);
+        let outcome = retry_with_backoff(
+            policy,
+            "test request",
+            || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into()),
+                    1 => response(503),
+                    2 => response(429),
+                    _ => response(200),
+                }
+            },
+            transient_response,
+        )
+        .await
+        .expect("eventually succeeds");
+        assert_eq!(outcome.status, 200);
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+        // Client errors other than 429 surface at once, and retries stop at the limit.
+        for (status, expected_attempts) in [(404, 1), (502, 4)] {
+            let attempts = AtomicUsize::new(0);
+            let outcome = retry_with_backoff(
+                policy,
+                "test request",
+                || async {
+                    attempts.fetch_add(1, Ordering::SeqCst);
+                    response(status)
+                },
+                transient_response,
+            )
+            .await
+            .expect("response");
+            assert_eq!(outcome.status, status);
+            assert_eq!(attempts.load(Ordering::SeqCst), expected_attempts);
+        }
+
+        assert!(!transient_response(&Err(anyhow!("invalid `x` header"))));
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_and_a_cap() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+        };
+        for retry in 0..3 {
+            let full = Duration::from_millis(100 * 2u64.pow(retry));
+            let delay = policy.delay(retry);
+            assert!(
+                delay >= full / 2 && delay <= full,
+                "{delay:?} for retry {retry}"
+            );
+        }
+        assert!(policy.delay(30) <= MAX_RETRY_DELAY);
+    }
+}
@@ -0,0 +1,147 @@
+//! Incremental Server-Sent Events parser for the Duck.ai chat stream.
+
+/// Splits an SSE byte stream into `data` payloads.
+///
+/// Bytes may arrive in arbitrary fragments: lines are only decoded once complete, so
+/// multi-byte UTF-8 sequences and `\r\n` pairs split across chunks are handled. Events end
+/// at a blank line; multiple `data:` lines in one event are joined with `\n`, and comment
+/// lines (starting with `:`) and other fields are ignored.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl SseParser {
+    /// Feeds a chunk of bytes and returns the payloads of every event it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut payloads = Vec::new();
+        let mut start = 0;
+        while let Some(offset) = self.buffer[start..]
+            .iter()
+            .position(|&byte| byte == b'\n' || byte == b'\r')
+        {
+            let end = start + offset;
+            let next = match self.buffer[end] {
+                b'\r' => match self.buffer.get(end + 1) {
+                    Some(b'\n') => end + 2,
+                    Some(_) => end + 1,
+                    // A trailing `\r` may be the first half of `\r\n`; wait for more bytes.
+                    None => break,
+                },
+                _ => end + 1,
+            };
+            let line = String::from_utf8_lossy(&self.buffer[start..end]).into_owned();
+            self.process_line(&line, &mut payloads);
+            start = next;
+        }
+        self.buffer.drain(..start);
+
+        payloads
+    }
+
+    /// Flushes an unterminated final line and any event still being assembled.
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut payloads = Vec::new();
+        let rest = std::mem::take(&mut self.buffer);
+        let line = String::from_utf8_lossy(&rest);
+        let line = line.trim_end_matches('\r');
+        if !line.is_empty() {
+            self.process_line(line, &mut payloads);
+        }
+        self.process_line("", &mut payloads);
+        payloads
+    }
+
+    fn process_line(&mut self, line: &str, payloads: &mut Vec<String>) {
+        if line.is_empty() {
+            if !self.data.is_empty() {
+                payloads.push(self.data.join("\n"));
+                self.data.clear();
+            }
+            return;
+        }
+        if line.starts_with(':') {
+            return;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        if field == "data" {
+            self.data.push(value.to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_in_fragments(input: &[u8], size: usize) -> Vec<String> {
+        let mut parser = SseParser::default();
+        let mut payloads = Vec::new();
+        for fragment in input.chunks(size) {
+            payloads.extend(parser.push(fragment));
+        }
+        payloads.extend(parser.finish());
+        payloads
+    }
+
+    #[test]
+    fn splits_lf_and_crlf_events() {
+        let input = b"data: {\"a\":1}\n\ndata: {\"b\":2}\r\n\r\ndata: [DONE]\n\n";
+        assert_eq!(
+            parse_in_fragments(input, input.len()),
+            vec![r#"{"a":1}"#, r#"{"b":2}"#, "[DONE]"]
+        );
+    }
+
+    #[test]
+    fn joins_multi_line_data_and_skips_comments() {
+        let input = b": keep-alive\nevent: message\ndata: first\ndata:second\nid: 7\n\n";
+        assert_eq!(parse_in_fragments(input, 64), vec!["first\nsecond"]);
+    }
+
+    #[test]
+    fn byte_by_byte_matches_whole_input() {
+        let input = "data: {\"message\":\"héllo 🦆\"}\r\n\r\n: ping\r\n\r\ndata: {\"message\":\"wörld\"}\n\ndata: [DONE]\n\n"
+            .as_bytes();
+        let whole = parse_in_fragments(input, input.len());
+        assert_eq!(
+            whole,
+            vec![
+                r#"{"message":"héllo 🦆"}"#,
+                r#"{"message":"wörld"}"#,
+                "[DONE]"
+            ]
+        );
+        for size in 1..8 {
+            assert_eq!(
+                parse_in_fragments(input, size),
+                whole,
+                "fragment size {size}"
+            );
+        }
+    }
+
+    #[test]
+    fn partial_block_is_held_until_terminated() {
+        let mut parser = SseParser::default();
+        assert!(parser.push(b"data: par").is_empty());
+        assert!(parser.push(b"tial\r").is_empty());
+        assert!(parser.push(b"\n").is_empty());
+        assert_eq!(parser.push(b"\r\n"), vec!["partial"]);
+        assert!(parser.finish().is_empty());
+    }
+
+    #[test]
+    fn finish_flushes_unterminated_event() {
+        let mut parser = SseParser::default();
+        assert_eq!(parser.push(b"data: one\n\ndata: tail"), vec!["one"]);
+        assert_eq!(parser.finish(), vec!["tail"]);
+    }
+}
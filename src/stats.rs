@@ -0,0 +1,103 @@
+//! Request counters reported by the server's `GET /admin/stats` endpoint.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::chat::ChatResponse;
+
+/// Process-wide server counters.
+#[derive(Debug)]
+pub struct ServerStats {
+    started: Instant,
+    requests: AtomicU64,
+    active_streams: AtomicU64,
+    challenges: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            active_streams: AtomicU64::new(0),
+            challenges: AtomicU64::new(0),
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the challenge behind an upstream response, if one was issued.
+    pub fn record_response(&self, response: &ChatResponse) {
+        if response.challenged {
+            self.challenges.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a stream as active until the returned guard is dropped.
+    pub fn stream_started(self: &Arc<Self>) -> ActiveStream {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+        ActiveStream(self.clone())
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn active_streams(&self) -> u64 {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    pub fn challenges(&self) -> u64 {
+        self.challenges.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keeps a stream counted in [`ServerStats::active_streams`] while alive.
+#[derive(Debug)]
+pub struct ActiveStream(Arc<ServerStats>);
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_streams_requests_and_challenges() {
+        let stats = Arc::new(ServerStats::new());
+        stats.record_request();
+        let guard = stats.stream_started();
+        assert_eq!(stats.active_streams(), 1);
+        drop(guard);
+        assert_eq!(stats.active_streams(), 0);
+
+        let mut response = ChatResponse {
+            status: 200,
+            body: String::new(),
+            completed: true,
+            challenged: false,
+        };
+        stats.record_response(&response);
+        response.challenged = true;
+        stats.record_response(&response);
+        assert_eq!(stats.requests(), 1);
+        assert_eq!(stats.challenges(), 1);
+    }
+}
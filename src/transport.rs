@@ -0,0 +1,302 @@
+//! HTTP transport used by [`HttpSession`](crate::session::HttpSession).
+//!
+//! Every upstream call (status, homepage, chat stream, challenge tiles and verification)
+//! goes through [`Transport::send`], so tests can swap reqwest for a scripted transport.
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Method, Url};
+use serde::de::DeserializeOwned;
+
+use crate::error::Result;
+
+/// Sends upstream requests. Implementations must be cheap to share across tasks.
+pub trait Transport: Debug + Send + Sync {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse>>;
+}
+
+/// A request to Duck.ai. Session-wide headers (user agent, client hints) are added by the
+/// transport; these are only the per-request ones.
+#[derive(Debug, Clone)]
+pub struct TransportRequest {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+    /// Replaces the session-wide timeout for this request only.
+    pub timeout: Option<Duration>,
+}
+
+impl TransportRequest {
+    pub fn get(url: Url) -> Self {
+        Self {
+            method: Method::GET,
+            url,
+            headers: HeaderMap::new(),
+            body: None,
+            timeout: None,
+        }
+    }
+
+    pub fn post(url: Url, body: Vec<u8>) -> Self {
+        Self {
+            method: Method::POST,
+            url,
+            headers: HeaderMap::new(),
+            body: Some(body),
+            timeout: None,
+        }
+    }
+
+    /// Adds a header; an invalid name or value is an error. Names are case-insensitive.
+    pub fn header(mut self, name: &str, value: &str) -> Result<Self> {
+        let header = HeaderName::from_bytes(name.as_bytes())
+            .with_context(|| format!("invalid header name `{name}`"))?;
+        let value =
+            HeaderValue::from_str(value).with_context(|| format!("invalid `{name}` header"))?;
+        self.headers.insert(header, value);
+        Ok(self)
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// Response status and headers, with the body left as a stream of chunks.
+pub struct TransportResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: BoxStream<'static, Result<Bytes>>,
+}
+
+impl TransportResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Response header as text, if present and valid.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub async fn bytes(self) -> Result<Vec<u8>> {
+        self.body
+            .try_fold(Vec::new(), |mut buf, chunk| async move {
+                buf.extend_from_slice(&chunk);
+                Ok(buf)
+            })
+            .await
+    }
+
+    pub async fn text(self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes().await?).into_owned())
+    }
+
+    pub async fn json<T: DeserializeOwned>(self) -> Result<T> {
+        let bytes = self.bytes().await?;
+        serde_json::from_slice(&bytes).map_err(|err| anyhow!("decoding JSON body: {err}"))
+    }
+}
+
+/// Transport over the session's configured `reqwest::Client`.
+#[derive(Debug, Clone)]
+pub struct ReqwestTransport {
+    client: Client,
+}
+
+impl ReqwestTransport {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse>> {
+        Box::pin(async move {
+            let mut builder = self
+                .client
+                .request(request.method, request.url)
+                .headers(request.headers);
+            if let Some(body) = request.body {
+                builder = builder.body(body);
+            }
+            if let Some(timeout) = request.timeout {
+                builder = builder.timeout(timeout);
+            }
+            let response = builder.send().await?;
+            Ok(TransportResponse {
+                status: response.status().as_u16(),
+                headers: response.headers().clone(),
+                body: response.bytes_stream().map_err(anyhow::Error::from).boxed(),
+            })
+        })
+    }
+}
+
+/// Shared fixtures for tests that drive a session without the network.
+#[cfg(test)]
+pub mod testing {
+    use std::collections::VecDeque;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::session::SessionConfig;
+
+    /// Session settings used across tests: a fixed user agent and a 5 second timeout.
+    pub fn test_config() -> SessionConfig {
+        SessionConfig::new("TestUA/1.0".to_owned(), Duration::from_secs(5))
+    }
+
+    /// Per-process path in the temp directory, so parallel test runs don't collide.
+    pub fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("duckai-{}-{name}", std::process::id()))
+    }
+
+    /// One scripted answer from [`ScriptedTransport`].
+    #[derive(Debug, Clone)]
+    pub enum Reply {
+        /// Fails as if the connection was reset.
+        Reset,
+        /// Fails with the given message.
+        Fail(&'static str),
+        /// Answers with the status, headers and body chunks.
+        Body {
+            status: u16,
+            headers: Vec<(&'static str, &'static str)>,
+            chunks: Vec<&'static str>,
+        },
+        /// Answers 200 with the chunk repeated forever.
+        Endless(&'static str),
+    }
+
+    impl Reply {
+        /// A 200 SSE body sent as a single chunk.
+        pub fn sse(body: &'static str) -> Self {
+            Self::Body {
+                status: 200,
+                headers: Vec::new(),
+                chunks: vec![body],
+            }
+        }
+    }
+
+    /// Answers requests from a script, repeating the last reply once the rest are used up,
+    /// and keeps every request it saw.
+    #[derive(Debug)]
+    pub struct ScriptedTransport {
+        replies: Mutex<VecDeque<Reply>>,
+        requests: Mutex<Vec<TransportRequest>>,
+    }
+
+    impl ScriptedTransport {
+        pub fn new(replies: impl IntoIterator<Item = Reply>) -> Self {
+            let replies: VecDeque<_> = replies.into_iter().collect();
+            assert!(!replies.is_empty(), "a script needs at least one reply");
+            Self {
+                replies: Mutex::new(replies),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+
+        pub fn requests(&self) -> Vec<TransportRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn next_reply(&self) -> Reply {
+            let mut replies = self.replies.lock().unwrap();
+            if replies.len() > 1 {
+                replies.pop_front().unwrap()
+            } else {
+                replies[0].clone()
+            }
+        }
+    }
+
+    impl Transport for ScriptedTransport {
+        fn send(&self, request: TransportRequest) -> BoxFuture<'_, Result<TransportResponse>> {
+            self.requests.lock().unwrap().push(request);
+            let reply = self.next_reply();
+            Box::pin(async move {
+                let (status, headers, body) = match reply {
+                    Reply::Reset => {
+                        return Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset).into())
+                    }
+                    Reply::Fail(message) => return Err(anyhow!(message)),
+                    Reply::Body {
+                        status,
+                        headers,
+                        chunks,
+                    } => {
+                        let chunks = chunks.into_iter().map(|chunk| Ok(Bytes::from(chunk)));
+                        (status, headers, futures_util::stream::iter(chunks).boxed())
+                    }
+                    Reply::Endless(chunk) => {
+                        let chunk = Bytes::from(chunk);
+                        let body = futures_util::stream::repeat_with(move || Ok(chunk.clone()));
+                        (200, Vec::new(), body.boxed())
+                    }
+                };
+                let headers = headers
+                    .into_iter()
+                    .map(|(name, value)| {
+                        (
+                            HeaderName::from_static(name),
+                            HeaderValue::from_static(value),
+                        )
+                    })
+                    .collect();
+                Ok(TransportResponse {
+                    status,
+                    headers,
+                    body,
+                })
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{Reply, ScriptedTransport};
+    use super::*;
+
+    #[test]
+    fn header_names_are_validated_instead_of_panicking() {
+        let url: Url = "https://duck.test/".parse().unwrap();
+        let request = TransportRequest::get(url.clone())
+            .header("X-Custom", "1")
+            .unwrap();
+        assert_eq!(request.headers["x-custom"], "1");
+
+        let err = TransportRequest::get(url.clone())
+            .header("bad header", "1")
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid header name"));
+        assert!(TransportRequest::get(url).header("x-ok", "a\nb").is_err());
+    }
+
+    #[tokio::test]
+    async fn scripted_transport_repeats_its_last_reply() {
+        let transport = ScriptedTransport::new([Reply::Reset, Reply::sse("ok")]);
+        let url: Url = "https://duck.test/".parse().unwrap();
+        assert!(transport
+            .send(TransportRequest::get(url.clone()))
+            .await
+            .is_err());
+        for _ in 0..2 {
+            let response = transport.send(TransportRequest::get(url.clone())).await;
+            assert_eq!(response.unwrap().text().await.unwrap(), "ok");
+        }
+        assert_eq!(transport.requests().len(), 3);
+    }
+}
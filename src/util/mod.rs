@@ -1,4 +1,8 @@
+use std::borrow::Cow;
 use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
 use base64::Engine;
@@ -8,6 +12,8 @@ use sha2::{Digest, Sha256};
 
 static CHROME_VERSION_RE: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"Chrome/(\d{2,3})").expect("regex should compile"));
+static CHROME_FULL_VERSION_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"Chrome/(\d{2,3}\.\d+\.\d+\.\d+)").expect("regex should compile"));
 
 /// Extracts the Chrome major version from a UA string (defaulting to `"140"`).
 pub fn chrome_major_version(ua: &str) -> String {
@@ -18,6 +24,27 @@ pub fn chrome_major_version(ua: &str) -> String {
         .unwrap_or_else(|| "140".to_owned())
 }
 
+/// Chrome major version that shipped to stable on [`CHROME_BASELINE_RELEASE`].
+const CHROME_BASELINE_MAJOR: u32 = 140;
+/// Unix timestamp of the Chrome 140 stable release (2025-09-02).
+const CHROME_BASELINE_RELEASE: u64 = 1_756_771_200;
+/// Chrome ships a new major version to stable roughly every four weeks.
+const CHROME_RELEASE_CADENCE_SECS: u64 = 28 * 24 * 60 * 60;
+
+/// Estimates the current stable Chrome major version from the release cadence.
+pub fn estimate_chrome_major(now_unix_secs: u64) -> u32 {
+    let elapsed = now_unix_secs.saturating_sub(CHROME_BASELINE_RELEASE);
+    let releases = elapsed / CHROME_RELEASE_CADENCE_SECS;
+    CHROME_BASELINE_MAJOR + u32::try_from(releases).unwrap_or(0)
+}
+
+/// Builds the default desktop Chrome User-Agent for the given major version.
+pub fn chrome_user_agent(major: u32) -> String {
+    format!(
+        "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/{major}.0.0.0 Safari/537.36"
+    )
+}
+
 /// Best-effort platform detection for Sec-CH-UA-Platform.
 pub fn platform_token(ua: &str) -> &'static str {
     if ua.contains("Mac OS X") {
@@ -37,6 +64,44 @@ pub fn sec_ch_ua(ua: &str) -> String {
     format!(r#""Chromium";v="{major}", "Not=A?Brand";v="24", "Google Chrome";v="{major}""#)
 }
 
+/// Full Chrome version from the UA, kept consistent with [`chrome_major_version`].
+pub fn chrome_full_version(ua: &str) -> String {
+    let major = chrome_major_version(ua);
+    CHROME_FULL_VERSION_RE
+        .captures(ua)
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str().to_owned())
+        .filter(|full| full.split('.').next() == Some(major.as_str()))
+        .unwrap_or_else(|| format!("{major}.0.0.0"))
+}
+
+/// Builds a Sec-CH-UA-Full-Version-List header string matching [`sec_ch_ua`].
+pub fn sec_ch_ua_full_version_list(ua: &str) -> String {
+    let full = chrome_full_version(ua);
+    format!(r#""Chromium";v="{full}", "Not=A?Brand";v="24.0.0.0", "Google Chrome";v="{full}""#)
+}
+
+/// Best-effort CPU architecture for Sec-CH-UA-Arch.
+pub fn sec_ch_ua_arch(ua: &str) -> &'static str {
+    let lower = ua.to_ascii_lowercase();
+    if lower.contains("aarch64") || lower.contains("arm") {
+        r#""arm""#
+    } else {
+        r#""x86""#
+    }
+}
+
+/// Best-effort pointer width for Sec-CH-UA-Bitness.
+pub fn sec_ch_ua_bitness(ua: &str) -> &'static str {
+    let lower = ua.to_ascii_lowercase();
+    const MARKERS: [&str; 6] = ["win64", "wow64", "x86_64", "x64", "aarch64", "mac os x"];
+    if MARKERS.iter().any(|marker| lower.contains(marker)) {
+        r#""64""#
+    } else {
+        r#""32""#
+    }
+}
+
 /// Computes a SHA-256 digest encoded as standard Base64.
 pub fn sha256_base64(value: impl AsRef<[u8]>) -> String {
     let mut hasher = Sha256::new();
@@ -45,6 +110,100 @@ pub fn sha256_base64(value: impl AsRef<[u8]>) -> String {
     BASE64_STANDARD.encode(digest)
 }
 
+/// Approximate number of characters per model token, used where no tokenizer is available.
+pub const CHARS_PER_TOKEN: usize = 4;
+
+/// Cuts `text` to at most `max_chars` characters, reporting whether anything was dropped.
+pub fn truncate_chars(text: &str, max_chars: usize) -> (&str, bool) {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => (&text[..end], true),
+        None => (text, false),
+    }
+}
+
+/// Which part of an oversized prompt `--truncate-prompt` drops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TruncateSide {
+    /// Drop the beginning, keeping the most recent text.
+    Head,
+    /// Drop the end.
+    Tail,
+    /// Drop the middle, keeping both ends.
+    Middle,
+}
+
+/// Trims `text` to `max_chars` characters from `side`, returning it with the dropped count.
+pub fn trim_to_chars(text: &str, max_chars: usize, side: TruncateSide) -> (String, usize) {
+    let total = text.chars().count();
+    if total <= max_chars {
+        return (text.to_owned(), 0);
+    }
+    let dropped = total - max_chars;
+    let trimmed = match side {
+        TruncateSide::Head => text.chars().skip(dropped).collect(),
+        TruncateSide::Tail => text.chars().take(max_chars).collect(),
+        TruncateSide::Middle => {
+            let head = max_chars / 2;
+            let tail = max_chars - head;
+            text.chars()
+                .take(head)
+                .chain(text.chars().skip(total - tail))
+                .collect()
+        }
+    };
+    (trimmed, dropped)
+}
+
+/// Summarizes text by length and SHA-256 digest so logs never carry the raw content.
+pub fn redact(text: &str) -> String {
+    format!(
+        "<redacted len={} sha256={}>",
+        text.chars().count(),
+        sha256_base64(text)
+    )
+}
+
+/// Seconds since the Unix epoch, or 0 if the clock is set before it.
+pub fn unix_now() -> u64 {
+    unix_elapsed().as_secs()
+}
+
+/// Milliseconds since the Unix epoch, or 0 if the clock is set before it.
+pub fn unix_millis() -> u128 {
+    unix_elapsed().as_millis()
+}
+
+fn unix_elapsed() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Writes a file holding session secrets (cookies, VQD tokens) so that on Unix only its
+/// owner can read it, tightening the mode of an existing file as well.
+pub fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(bytes)
+}
+
+/// Returns the text itself, or its redacted summary when `redact` is set.
+pub fn loggable(text: &str, redact_enabled: bool) -> Cow<'_, str> {
+    if redact_enabled {
+        Cow::Owned(redact(text))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
 /// Parses user-provided selections into a deduplicated set of indices.
 pub fn parse_tile_selection(input: &str, len: usize) -> Vec<usize> {
     let mut indices = BTreeSet::new();
@@ -77,6 +236,15 @@ mod tests {
         assert_eq!(chrome_major_version(ua), "140");
     }
 
+    #[test]
+    fn estimates_chrome_version_from_cadence() {
+        assert_eq!(estimate_chrome_major(0), 140);
+        assert_eq!(estimate_chrome_major(CHROME_BASELINE_RELEASE), 140);
+        let a_year_later = CHROME_BASELINE_RELEASE + 365 * 24 * 60 * 60;
+        assert_eq!(estimate_chrome_major(a_year_later), 153);
+        assert_eq!(chrome_major_version(&chrome_user_agent(153)), "153");
+    }
+
     #[test]
     fn platform_detection_variants() {
         assert_eq!(platform_token("...Mac OS X..."), "macOS");
@@ -93,12 +261,66 @@ mod tests {
         assert!(header.contains(r#""Google Chrome";v="141""#));
     }
 
+    #[test]
+    fn full_version_hints_follow_major_version() {
+        let ua = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/141.0.7390.65 Safari/537.36";
+        assert_eq!(chrome_full_version(ua), "141.0.7390.65");
+        let list = sec_ch_ua_full_version_list(ua);
+        assert!(list.contains(r#""Google Chrome";v="141.0.7390.65""#));
+        assert_eq!(sec_ch_ua_arch(ua), r#""x86""#);
+        assert_eq!(sec_ch_ua_bitness(ua), r#""64""#);
+
+        assert_eq!(chrome_full_version("Chrome/141.0.0.0"), "141.0.0.0");
+        assert_eq!(chrome_full_version("UnknownAgent/1.0"), "140.0.0.0");
+        assert_eq!(sec_ch_ua_arch("X11; Linux aarch64"), r#""arm""#);
+        assert_eq!(
+            sec_ch_ua_bitness("Windows NT 10.0; Chrome/141.0.1.64"),
+            r#""32""#
+        );
+    }
+
     #[test]
     fn hashes_base64() {
         let digest = sha256_base64("hello");
         assert_eq!(digest, "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=");
     }
 
+    #[test]
+    fn truncates_by_chars() {
+        assert_eq!(truncate_chars("héllo", 2), ("hé", true));
+        assert_eq!(truncate_chars("héllo", 5), ("héllo", false));
+    }
+
+    #[test]
+    fn trims_prompt_from_each_side() {
+        let text = "abcdéfgh";
+        assert_eq!(
+            trim_to_chars(text, 4, TruncateSide::Head),
+            ("éfgh".to_owned(), 4)
+        );
+        assert_eq!(
+            trim_to_chars(text, 4, TruncateSide::Tail),
+            ("abcd".to_owned(), 4)
+        );
+        assert_eq!(
+            trim_to_chars(text, 5, TruncateSide::Middle),
+            ("abfgh".to_owned(), 3)
+        );
+        assert_eq!(
+            trim_to_chars(text, 9, TruncateSide::Middle),
+            (text.to_owned(), 0)
+        );
+    }
+
+    #[test]
+    fn redacts_content_to_length_and_hash() {
+        let summary = redact("hello");
+        assert!(!summary.contains("hello"));
+        assert!(summary.contains("len=5"));
+        assert!(summary.contains("LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="));
+        assert_eq!(loggable("hello", false), "hello");
+    }
+
     #[test]
     fn parses_tile_indices() {
         let input = "0, 3 4, 4, 2";
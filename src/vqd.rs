@@ -1,17 +1,24 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
 use anyhow::{anyhow, Context};
 use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
 use base64::Engine;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
 use crate::error::Result;
 use crate::js;
 use crate::model::{EvaluatedHashes, StatusResponse};
-use crate::session::HttpSession;
-use crate::util::sha256_base64;
+use crate::session::{retry_with_backoff, transient_response, HttpSession};
+use crate::transport::TransportRequest;
+use crate::util::{self, sha256_base64};
 
 /// Represents session preparation output including hashes and FE metadata.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VqdSession {
     pub vqd_header: String,
     pub fe_version: String,
@@ -27,17 +34,135 @@ struct StatusData {
     body: StatusResponse,
 }
 
+/// Where `prepare_session` keeps a prepared session on disk (`--vqd-cache`) and how long
+/// it may be reused (`--vqd-ttl`).
+#[derive(Debug, Clone)]
+pub struct VqdCacheOptions {
+    pub path: PathBuf,
+    pub ttl: Duration,
+}
+
+impl VqdCacheOptions {
+    /// Deletes the cache file after upstream rejected the session it holds, so the next run
+    /// performs a fresh handshake instead of failing the same way until the TTL runs out.
+    pub fn discard(&self) {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => tracing::warn!(
+                path = %self.path.display(),
+                "upstream rejected the cached VQD session; removed the cache file"
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => tracing::warn!(
+                path = %self.path.display(),
+                error = %err,
+                "could not remove the rejected VQD cache file"
+            ),
+        }
+    }
+}
+
+/// A prepared session as stored in the `--vqd-cache` file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VqdCache {
+    /// Unix seconds when the session was prepared.
+    pub created_at: u64,
+    /// Lifetime the session was saved with.
+    pub ttl_secs: u64,
+    pub session: VqdSession,
+}
+
+impl VqdCache {
+    fn new(session: VqdSession, ttl: Duration) -> Self {
+        Self {
+            created_at: util::unix_now(),
+            ttl_secs: ttl.as_secs(),
+            session,
+        }
+    }
+
+    /// Reads a cache file. A missing or unreadable file is `None`; a corrupt one is logged
+    /// and also `None`, so preparation falls back to a fresh handshake.
+    pub fn load(path: &Path) -> Option<Self> {
+        let bytes = std::fs::read(path).ok()?;
+        match serde_json::from_slice(&bytes) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = %err,
+                    "ignoring corrupt VQD cache file"
+                );
+                None
+            }
+        }
+    }
+
+    fn store(&self, path: &Path) -> Result<()> {
+        let bytes = serde_json::to_vec_pretty(self)?;
+        util::write_private(path, &bytes)
+            .with_context(|| format!("writing VQD cache {}", path.display()))
+    }
+
+    /// Whether the session is younger than both its saved lifetime and `ttl`.
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        let age = util::unix_now().saturating_sub(self.created_at);
+        age < self.ttl_secs.min(ttl.as_secs())
+    }
+}
+
+/// Prepares a VQD session, reusing the one in `cache` while it is fresh. A fresh handshake
+/// is written back to the cache.
+pub async fn prepare_session(
+    session: &HttpSession,
+    cache: Option<&VqdCacheOptions>,
+) -> Result<VqdSession> {
+    if session.mock_response().is_some() {
+        return Ok(crate::mock::vqd_session());
+    }
+
+    if let Some(options) = cache {
+        if let Some(cached) =
+            VqdCache::load(&options.path).filter(|cached| cached.is_fresh(options.ttl))
+        {
+            tracing::debug!(path = %options.path.display(), "reusing cached VQD session");
+            return Ok(cached.session);
+        }
+    }
+
+    let vqd = handshake(session).await?;
+    if let Some(options) = cache {
+        if let Err(err) = VqdCache::new(vqd.clone(), options.ttl).store(&options.path) {
+            tracing::warn!(error = %err, "could not save the VQD cache");
+        }
+    }
+    Ok(vqd)
+}
+
 /// Full VQD preparation sequence: status fetch, script evaluation, and FE metadata parsing.
-pub async fn prepare_session(session: &HttpSession) -> Result<VqdSession> {
+async fn handshake(session: &HttpSession) -> Result<VqdSession> {
     let status = fetch_status(session).await?;
-    let eval = evaluate_script(&status.script_b64, session.user_agent()).await?;
+    let eval = evaluate_script(
+        &status.script_b64,
+        session.user_agent(),
+        session.js_cancel(),
+    )
+    .await?;
     let hashed_client = eval
         .client_hashes
         .iter()
-        .map(|value| sha256_base64(value))
+        .map(sha256_base64)
         .collect::<Vec<_>>();
     let vqd_header = encode_vqd_header(&eval, &hashed_client)?;
-    let fe_version = fetch_fe_version(session).await?;
+    let fe_version = match FE_VERSION_CACHE.get() {
+        Some(version) => version,
+        None => {
+            let version = fetch_fe_version(session).await?;
+            if !version.is_empty() {
+                FE_VERSION_CACHE.store(&version);
+            }
+            version
+        }
+    };
 
     Ok(VqdSession {
         vqd_header,
@@ -52,23 +177,26 @@ pub async fn prepare_session(session: &HttpSession) -> Result<VqdSession> {
 async fn fetch_status(session: &HttpSession) -> Result<StatusData> {
     let url = session
         .base_url()
-        .join("duckchat/v1/status")
+        .join(&session.paths().status)
         .context("invalid status url")?;
-    let response = session
-        .client()
-        .get(url)
-        .header("Accept", "application/json")
-        .header("x-vqd-accept", "1")
-        .send()
-        .await
-        .context("requesting /duckchat/v1/status")?;
+    let request = TransportRequest::get(url)
+        .header("accept", "application/json")?
+        .header("x-vqd-accept", "1")?;
+    let response = retry_with_backoff(
+        session.retry_policy(),
+        "status request",
+        || session.transport().send(request.clone()),
+        transient_response,
+    )
+    .await
+    .with_context(|| format!("requesting /{}", session.paths().status))?;
 
-    if !response.status().is_success() {
-        return Err(anyhow!("status request failed: {}", response.status()));
+    if !response.is_success() {
+        return Err(anyhow!("status request failed: {}", response.status));
     }
 
-    let headers = response.headers();
-    let script_b64 = headers
+    let script_b64 = response
+        .headers
         .get("x-vqd-hash-1")
         .ok_or_else(|| anyhow!("status response missing x-vqd-hash-1 header"))?
         .to_str()
@@ -80,8 +208,12 @@ async fn fetch_status(session: &HttpSession) -> Result<StatusData> {
     Ok(StatusData { script_b64, body })
 }
 
-async fn evaluate_script(script_b64: &str, ua: &str) -> Result<EvaluatedHashes> {
-    js::evaluate(script_b64, ua).context("executing VQD script via embedded JS runtime")
+async fn evaluate_script(
+    script_b64: &str,
+    ua: &str,
+    cancel: &AtomicBool,
+) -> Result<EvaluatedHashes> {
+    js::evaluate(script_b64, ua, cancel).context("executing VQD script via embedded JS runtime")
 }
 
 fn encode_vqd_header(eval: &EvaluatedHashes, hashed_client: &[String]) -> Result<String> {
@@ -95,23 +227,122 @@ fn encode_vqd_header(eval: &EvaluatedHashes, hashed_client: &[String]) -> Result
     Ok(encoded)
 }
 
+/// How long a scraped fe-version is reused before the homepage is fetched again.
+const FE_VERSION_TTL: Duration = Duration::from_secs(120);
+
+/// Process-wide fe-version memo so session preparations close together share one homepage
+/// scrape, independently of the VQD cache TTL.
+static FE_VERSION_CACHE: Lazy<FeVersionCache> = Lazy::new(|| FeVersionCache::new(FE_VERSION_TTL));
+
+/// Makes the next session preparation scrape the fe-version again instead of reusing the
+/// memo. Called when upstream rejects a session, in case a stale fe-version caused it.
+pub fn expire_fe_version() {
+    FE_VERSION_CACHE.expire();
+}
+
+struct FeVersionCache {
+    ttl: Duration,
+    /// Version and when it was fetched; the time is cleared by [`expire`](Self::expire).
+    entry: Mutex<Option<(String, Option<Instant>)>>,
+}
+
+impl FeVersionCache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entry: Mutex::new(None),
+        }
+    }
+
+    fn get(&self) -> Option<String> {
+        let entry = self.entry.lock().ok()?;
+        entry
+            .as_ref()
+            .filter(|(_, fetched_at)| fetched_at.is_some_and(|at| at.elapsed() < self.ttl))
+            .map(|(version, _)| version.clone())
+    }
+
+    /// Most recent version regardless of age.
+    fn last_known(&self) -> Option<String> {
+        let entry = self.entry.lock().ok()?;
+        entry.as_ref().map(|(version, _)| version.clone())
+    }
+
+    fn store(&self, version: &str) {
+        if let Ok(mut entry) = self.entry.lock() {
+            *entry = Some((version.to_owned(), Some(Instant::now())));
+        }
+    }
+
+    /// Stops [`get`](Self::get) returning the version while keeping it as the last known one.
+    fn expire(&self) {
+        if let Ok(mut entry) = self.entry.lock() {
+            if let Some((_, fetched_at)) = entry.as_mut() {
+                *fetched_at = None;
+            }
+        }
+    }
+}
+
 async fn fetch_fe_version(session: &HttpSession) -> Result<String> {
+    const QUERY: &str = "?q=DuckDuckGo+AI+Chat&ia=chat&duckai=1";
+
+    let html = fetch_homepage(session, QUERY, false).await?;
+    let err = match extract_fe_version(&html) {
+        Ok(version) => return Ok(version),
+        Err(err) => err,
+    };
+
+    // A stale CDN copy of the homepage can lack the markers; ask for a fresh one once.
+    tracing::warn!("fe-version markers missing ({err}); retrying with cache busting");
+    let busted = format!("{QUERY}&_={}", util::unix_millis());
+    let html = fetch_homepage(session, &busted, true).await?;
+    match extract_fe_version(&html) {
+        Ok(version) => {
+            tracing::info!("fe-version recovered after cache-busting retry");
+            Ok(version)
+        }
+        Err(err) if session.accept_invalid_fe_version() => {
+            let fallback = FE_VERSION_CACHE.last_known().unwrap_or_default();
+            tracing::warn!(
+                "fe-version markers still missing ({err}); continuing with {} fe-version \
+                 because of --accept-invalid-fe-version",
+                if fallback.is_empty() {
+                    "an empty"
+                } else {
+                    "the last known"
+                }
+            );
+            Ok(fallback)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn fetch_homepage(session: &HttpSession, query: &str, no_cache: bool) -> Result<String> {
     let url = session
         .base_url()
-        .join("?q=DuckDuckGo+AI+Chat&ia=chat&duckai=1")
+        .join(query)
         .context("invalid fe-version url")?;
 
-    let html = session
-        .client()
-        .get(url)
-        .send()
-        .await
-        .context("requesting DuckDuckGo homepage")?
-        .text()
-        .await
-        .context("reading homepage body")?;
+    let mut request = TransportRequest::get(url);
+    if no_cache {
+        request = request
+            .header("cache-control", "no-cache")?
+            .header("pragma", "no-cache")?;
+    }
 
-    extract_fe_version(&html)
+    retry_with_backoff(
+        session.retry_policy(),
+        "homepage request",
+        || session.transport().send(request.clone()),
+        transient_response,
+    )
+    .await
+    .context("requesting DuckDuckGo homepage")?
+    .text()
+    .await
+    .context("reading homepage body")
 }
 
 static BE_VERSION_RE: Lazy<Regex> =
@@ -146,8 +377,24 @@ fn extract_fe_version(html: &str) -> Result<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use base64::engine::general_purpose::STANDARD;
-    use base64::Engine;
+    use crate::transport::testing::{temp_path, test_config, Reply, ScriptedTransport};
+
+    #[test]
+    fn fe_version_cache_expires_after_ttl() {
+        let cache = FeVersionCache::new(Duration::from_secs(60));
+        assert_eq!(cache.get(), None);
+        cache.store("be-hash");
+        assert_eq!(cache.get().as_deref(), Some("be-hash"));
+
+        let expired = FeVersionCache::new(Duration::ZERO);
+        expired.store("be-hash");
+        assert_eq!(expired.get(), None);
+        assert_eq!(expired.last_known().as_deref(), Some("be-hash"));
+
+        cache.expire();
+        assert_eq!(cache.get(), None);
+        assert_eq!(cache.last_known().as_deref(), Some("be-hash"));
+    }
 
     #[test]
     fn extracts_fe_version_from_hash() {
@@ -178,7 +425,7 @@ mod tests {
     #[tokio::test]
     async fn evaluates_known_script() {
         let script_b64 = include_str!("../../script.b64").trim();
-        let result = evaluate_script(script_b64, "FakeUA/1.0")
+        let result = evaluate_script(script_b64, "FakeUA/1.0", &AtomicBool::new(false))
             .await
             .expect("script should evaluate successfully");
         assert_eq!(result.client_hashes[0], "FakeUA/1.0");
@@ -190,7 +437,56 @@ mod tests {
     #[tokio::test]
     async fn errors_for_invalid_script() {
         let bogus = BASE64_STANDARD.encode(b"hello");
-        let err = evaluate_script(&bogus, "UA").await.unwrap_err();
+        let err = evaluate_script(&bogus, "UA", &AtomicBool::new(false))
+            .await
+            .unwrap_err();
         assert!(err.to_string().contains("JS evaluation failed"));
     }
+
+    #[tokio::test]
+    async fn fresh_disk_cache_skips_the_handshake() {
+        let session = HttpSession::new(&test_config())
+            .expect("session")
+            .with_transport(std::sync::Arc::new(ScriptedTransport::new([Reply::Fail(
+                "offline",
+            )])));
+        let options = VqdCacheOptions {
+            path: temp_path("vqd.json"),
+            ttl: Duration::from_secs(60),
+        };
+
+        VqdCache::new(crate::mock::vqd_session(), options.ttl)
+            .store(&options.path)
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&options.path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let vqd = prepare_session(&session, Some(&options)).await.unwrap();
+        assert_eq!(vqd.vqd_header, "mock-vqd-header");
+
+        std::fs::write(&options.path, b"{ not json").unwrap();
+        let err = prepare_session(&session, Some(&options)).await.unwrap_err();
+        assert!(format!("{err:#}").contains("offline"));
+        options.discard();
+        assert!(!options.path.exists());
+    }
+
+    #[test]
+    fn disk_cache_honors_the_shorter_ttl() {
+        let cache = VqdCache::new(crate::mock::vqd_session(), Duration::from_secs(60));
+        assert!(cache.is_fresh(Duration::from_secs(300)));
+        assert!(!cache.is_fresh(Duration::ZERO));
+
+        let old = VqdCache {
+            created_at: util::unix_now() - 120,
+            ..cache
+        };
+        assert!(!old.is_fresh(Duration::from_secs(300)));
+    }
 }